@@ -100,16 +100,11 @@ local stages = {
     remove_camera_target()
 
     walk("_camera", "up", 4, 0.05)
-    walk_wait("bakery_girl", "up", 0.75, 0.08)
-    walk_wait("bakery_girl", "left", 8, 0.08)
-    walk_wait("bakery_girl", "up", 4.5, 0.08)
-    walk_wait("bakery_girl", "right", 6.5, 0.08)
+    -- To the oven and back around the counter
+    walk_path_wait("bakery_girl", 10, 4.25, 0.08)
     walk_wait("bakery_girl", "up", 0, 0.08)
     wait(1)
-    walk_wait("bakery_girl", "left", 6.5, 0.08)
-    walk_wait("bakery_girl", "down", 4.5, 0.08)
-    walk_wait("bakery_girl", "right", 8, 0.08)
-    walk_wait("bakery_girl", "down", 0.4, 0.08)
+    walk_path_wait("bakery_girl", 11.5, 9.15, 0.08)
     wait(0.5)
     message("\"Here's your bun!\"")
     wait(1)
@@ -150,22 +145,17 @@ end
 
 ---@script bathroom::door
 
-if get_story_var("bathroom::door::open") == 0 then
-  if get_story_var("bathroom::door::have_key") == 0 then
-    if get_story_var("school_kid::stage") == 2 then
-      message("There's a note on the door:")
-      message("\"Closed for repairs. If you need to get in, find me\n" ..
-        "in the gym.\" - Janitor")
+-- (Once the key is found, interacting opens the door instead)
+if get_door_state("bathroom::door") == "locked"
+  and get_story_var("bathroom::door::have_key") == 0 then
+  if get_story_var("school_kid::stage") == 2 then
+    message("There's a note on the door:")
+    message("\"Closed for repairs. If you need to get in, find me\n" ..
+      "in the gym.\" - Janitor")
 
-      if get_story_var("janitor::stage") == 1 then
-        set_story_var("janitor::stage", 2)
-      end
+    if get_story_var("janitor::stage") == 1 then
+      set_story_var("janitor::stage", 2)
     end
-  else
-    switch_dual_state_animation("bathroom::door", 2)
-    set_entity_solid("bathroom::door::blocker", false)
-
-    set_story_var("bathroom::door::open", 1)
   end
 end
 
@@ -180,9 +170,7 @@ if get_story_var("main::pen_found") == 0 then
   set_entity_world_pos("bakery_girl", "hallway", 7.5, 4.5)
   set_entity_solid("bakery_girl", false)
   set_story_var("bakery_girl::stage", 4)
-  set_entity_visible("hallway::bakery_fire", true)
-  set_entity_visible("hallway::bakery_firefighter", true)
-  set_entity_visible("hallway::bakery_water_jet", true)
+  set_visible_tag("bakery_fire_scene", true)
   set_entity_solid("hallway::bakery_entrance_blocker", true)
 
   set_entity_map_pos("janitor", 7, 12)
@@ -1,4 +1,4 @@
-use crate::ecs::Component;
+use crate::ecs::{Component, ComponentRegistry, EntityId, EntityIdentifier};
 use crate::math::{MapPos, MapUnits, PixelUnits, Rect, Vec2};
 use crate::misc::Direction;
 use crate::script;
@@ -8,26 +8,57 @@ use derived_deref::{Deref, DerefMut};
 use sdl2::mixer::Channel;
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
 // I think eventually components should be organized into their domains
 // Or should they go in the ecs module?
 
-// TODO door component
-// open, closed, locked enum state. anims and sprites. interact script.
-// get_door_state command. collision updated downstream from state.
-
-// TODO teleport component
-// for map links like doorways
+// Every serde component must be registered here to be usable by name (JSON loading and saving,
+// Lua add_component/remove_component, dev ui)
+pub fn register_components(registry: &mut ComponentRegistry) {
+    registry.register::<Name>();
+    registry.register::<Position>();
+    registry.register::<Velocity>();
+    registry.register::<Collision>();
+    registry.register::<SfxEmitter>();
+    registry.register::<SpriteComp>();
+    registry.register::<Facing>();
+    registry.register::<Walking>();
+    registry.register::<Camera>();
+    registry.register::<AnimationComp>();
+    registry.register::<CharacterAnims>();
+    registry.register::<DualStateAnims>();
+    registry.register::<NamedAnims>();
+    registry.register::<InteractionTrigger>();
+    registry.register::<CollisionTrigger>();
+    registry.register::<AreaTrigger>();
+    registry.register::<Parent>();
+    registry.register::<Tags>();
+    registry.register::<Teleport>();
+    registry.register::<Door>();
+}
 
 // A name is used to refer to entities in scripts or other external data sources
 // The actual non-optional, guaranteed-unique identifier is EntityId
 // Name is expected to be unique and immutable
+// (The ecs indexes entities by name, and rejects duplicate names, so the field is private to keep
+// it from being changed through a query. Renaming means adding a new Name component)
 #[derive(Deref, Debug, Clone, Serialize, Deserialize)]
-pub struct Name(pub String);
+pub struct Name(String);
 impl Component for Name {}
 
+impl Name {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into())
+    }
+}
+
+// Groups entities so that scripts can operate on all of them at once
+#[derive(Deref, DerefMut, Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Tags(pub HashSet<String>);
+impl Component for Tags {}
+
 #[derive(Deref, DerefMut, Default, Clone, Debug, Serialize, Deserialize)]
 pub struct Position(pub WorldPos);
 impl Component for Position {}
@@ -47,10 +78,14 @@ pub struct SpriteComp {
     pub forced_sprite: Option<Sprite>,
     #[default = true]
     pub visible: bool,
+    // Whether every ancestor in the entity's hierarchy is visible (set by the hierarchy system)
+    #[serde(skip)]
+    #[default = true]
+    pub parent_visible: bool,
 }
 impl Component for SpriteComp {}
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Sprite {
     pub spritesheet: String,
@@ -99,7 +134,7 @@ impl AnimationComp {
     }
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AnimationClip {
     pub frames: Vec<Sprite>,
@@ -143,6 +178,49 @@ pub enum DualStateAnimationState {
     SecondToFirst,
 }
 
+// A door that the player opens and closes by interacting with it (through an interaction trigger)
+// Its collision is solid unless it's open, and it plays the clip of its state, or the opening and
+// closing clips in between
+// Interacting with a locked door only opens it once its key story var is nonzero (or a script can
+// unlock it)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Door {
+    pub state: DoorState,
+    pub open: AnimationClip,
+    pub closed: AnimationClip,
+    // Looks closed if none
+    #[serde(default)]
+    pub locked: Option<AnimationClip>,
+    #[serde(default)]
+    pub opening: Option<AnimationClip>,
+    #[serde(default)]
+    pub closing: Option<AnimationClip>,
+    #[serde(default)]
+    pub key_story_var: Option<String>,
+    // The state the animation was last set for, to know when to play the opening and closing clips
+    #[serde(skip)]
+    pub animated_state: Option<DoorState>,
+}
+impl Component for Door {}
+
+impl Door {
+    pub fn clip(&self, state: DoorState) -> &AnimationClip {
+        match state {
+            DoorState::Open => &self.open,
+            DoorState::Closed => &self.closed,
+            DoorState::Locked => self.locked.as_ref().unwrap_or(&self.closed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DoorState {
+    Open,
+    Closed,
+    Locked,
+}
+
 #[derive(Deref, Clone, Serialize, Deserialize)]
 pub struct NamedAnims(pub HashMap<String, AnimationClip>);
 impl Component for NamedAnims {}
@@ -153,16 +231,20 @@ pub struct Walking {
     pub speed: f64,
     pub direction: Direction,
     pub destination: Option<MapPos>,
+    // Walked to in order after the destination, such as the rest of a path
+    pub waypoints: VecDeque<MapPos>,
 }
 impl Component for Walking {}
 
 #[derive(Default, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Camera {
-    // This should be an Option<EntityIdentifier> when the time comes
-    pub target_entity: Option<String>,
+    pub target_entity: Option<EntityIdentifier>,
     pub size: Vec2<f64, MapUnits>,
     pub clamp_to_map: bool,
+    // For sending MapChanged events
+    #[serde(skip)]
+    pub previous_map: Option<String>,
 }
 impl Component for Camera {}
 
@@ -182,7 +264,14 @@ pub struct SfxEmitter {
     pub channel: Option<Channel>,
     pub repeat: bool,
 }
-impl Component for SfxEmitter {}
+impl Component for SfxEmitter {
+    fn on_remove(&mut self) {
+        // Otherwise a repeating sfx would keep playing forever
+        if let Some(channel) = self.channel.take() {
+            channel.halt();
+        }
+    }
+}
 
 // Not serde (can't save or load, and doesn't appear in dev ui)
 pub struct SineOffsetAnimation {
@@ -207,14 +296,86 @@ pub struct CollisionTrigger {
 }
 impl Component for CollisionTrigger {}
 
-// TODO track player in/out and only trigger when player enters
+// TODO only trigger when player enters
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AreaTrigger {
     pub script_source: ScriptSource,
     pub hitbox: Vec2<f64, MapUnits>,
+    // For sending EnteredArea and ExitedArea events
+    #[serde(skip)]
+    pub player_inside: bool,
 }
 impl Component for AreaTrigger {}
 
+// Moves the player to the destination when they walk into the hitbox, for map links like doorways
+// (Moving to another map sends MapChanged)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Teleport {
+    pub destination: TeleportDestination,
+    pub hitbox: Vec2<f64, MapUnits>,
+    // Facing on arrival, or keep the current facing
+    #[serde(default)]
+    pub facing: Option<Direction>,
+    // Seconds to fade out (and as many to fade back in), or teleport instantly
+    #[serde(default)]
+    pub fade: Option<f64>,
+    // Story var condition like a script start condition, or always teleport
+    #[serde(default)]
+    pub condition: Option<String>,
+    // Only entering teleports, so that arriving inside another teleport doesn't bounce the player
+    // (Saved, so that loading a game with the player inside doesn't teleport them either)
+    #[serde(default)]
+    pub player_inside: bool,
+}
+impl Component for Teleport {}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TeleportDestination {
+    WorldPos(WorldPos),
+    // The position of another entity, such as a marker placed in LDtk
+    Entity(EntityIdentifier),
+}
+
+// A teleport in progress: fading out, moving the entity, then fading back in
+// Not serde (short-lived, like SineOffsetAnimation)
+pub struct Teleporting {
+    pub destination: WorldPos,
+    pub facing: Option<Direction>,
+    pub fade: Duration,
+    pub elapsed: Duration,
+    pub arrived: bool,
+}
+impl Component for Teleporting {}
+
+impl Teleporting {
+    // Opacity of the fade to black
+    pub fn fade_alpha(&self) -> f64 {
+        if self.fade.is_zero() {
+            return 0.;
+        }
+        let progress = self.elapsed.as_secs_f64() / self.fade.as_secs_f64();
+        (1. - (progress - 1.).abs()).clamp(0., 1.)
+    }
+}
+
+// Makes the entity a child of another entity
+// A child's position follows its parent's position plus offset, it's hidden while its parent is
+// hidden, and it's removed along with its parent
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Parent {
+    pub entity: EntityIdentifier,
+    #[serde(default)]
+    pub offset: Vec2<f64, MapUnits>,
+}
+impl Component for Parent {}
+
+// The children of an entity, kept up to date from Parent components by the hierarchy system
+// Not serde (derived from Parent, so it never needs saving)
+pub struct Children(pub Vec<EntityId>);
+impl Component for Children {}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum ScriptSource {
     File { filepath: String, name_in_file: Option<String> },
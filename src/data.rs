@@ -1,107 +1,62 @@
 use crate::components::{
-    AnimationClip, AnimationComp, Camera, CharacterAnims, Collision, Facing, InteractionTrigger,
-    Name, NamedAnims, Position, ScriptSource, SfxEmitter, Sprite, SpriteComp, Velocity, Walking,
+    AnimationClip, Camera, Collision, InteractionTrigger, Name, NamedAnims, Position, ScriptSource,
+    SfxEmitter, Sprite, Velocity, Walking,
 };
-use crate::ecs::{Ecs, EntityId};
+use crate::ecs::Ecs;
+use crate::loader::prefabs;
 use crate::math::{Rect, Vec2};
-use crate::misc::{CELL_SIZE, WINDOW_SIZE};
+use crate::misc::WINDOW_SIZE;
 use crate::world::WorldPos;
+use serde_json::Map;
 use std::collections::HashMap;
 
 pub const PLAYER_ENTITY_NAME: &str = "_player";
 pub const CAMERA_ENTITY_NAME: &str = "_camera";
 
-pub fn load_entities_from_source(ecs: &mut Ecs) {
+pub fn load_entities_from_source(ecs: &mut Ecs, cell_size: u32) {
     // Player
     let id = ecs.add_entity();
-    ecs.add_component(id, Name(PLAYER_ENTITY_NAME.to_string()));
+    ecs.add_component(id, Name::new(PLAYER_ENTITY_NAME));
     ecs.add_component(id, Position(WorldPos::new("overworld", 1.5, 2.5)));
-    ecs.add_component(id, Velocity::default());
-    ecs.add_component(id, SpriteComp::default());
-    ecs.add_component(id, Facing::default());
-    ecs.add_component(id, Walking::default());
-    ecs.add_component(id, Collision { hitbox: Vec2::new(7. / 16., 5. / 16.), solid: true });
-
-    let clip_from_row = |row: u32| AnimationClip {
-        frames: [8, 7, 6, 7]
-            .into_iter()
-            .map(|col: u32| Sprite {
-                spritesheet: "characters".to_string(),
-                rect: Rect::new(col * 16, row * 16, 16, 16),
-                anchor: Vec2::new(8, 13),
-            })
-            .collect(),
-        seconds_per_frame: 0.15,
-    };
-
-    ecs.add_component(id, AnimationComp::default());
-    ecs.add_component(
-        id,
-        CharacterAnims {
-            up: clip_from_row(3),
-            down: clip_from_row(0),
-            left: clip_from_row(1),
-            right: clip_from_row(2),
-        },
-    );
-
-    ecs.add_component(
-        id,
-        NamedAnims(HashMap::from([(
-            "spin".to_string(),
-            AnimationClip {
-                frames: [(6, 0), (6, 1), (6, 2), (6, 3)]
-                    .into_iter()
-                    .map(|(col, row)| Sprite {
-                        spritesheet: "characters".to_string(),
-                        rect: Rect::new(col * 16, row * 16, 16, 16),
-                        anchor: Vec2::new(8, 13),
-                    })
-                    .collect(),
-                seconds_per_frame: 0.1,
-            },
-        )])),
-    );
+    prefabs::add_prefab_components(ecs, id, "player", &Map::new())
+        .unwrap_or_else(|e| log::error!("Couldn't add player prefab (err: {e})"));
 
     // Camera
     let id = ecs.add_entity();
-    ecs.add_component(id, Name(CAMERA_ENTITY_NAME.to_string()));
+    ecs.add_component(id, Name::new(CAMERA_ENTITY_NAME));
     ecs.add_component(
         id,
         Camera {
-            target_entity: Some(PLAYER_ENTITY_NAME.to_string()),
+            target_entity: Some(PLAYER_ENTITY_NAME.into()),
             size: Vec2::new(
                 // TODO zoom variable
-                WINDOW_SIZE.x as f64 / CELL_SIZE as f64 / 4.,
-                WINDOW_SIZE.y as f64 / CELL_SIZE as f64 / 4.,
+                WINDOW_SIZE.x as f64 / cell_size as f64 / 4.,
+                WINDOW_SIZE.y as f64 / cell_size as f64 / 4.,
             ),
             clamp_to_map: true,
+            previous_map: None,
         },
     );
     ecs.add_component(id, Position::default());
     ecs.add_component(id, Velocity::default());
     ecs.add_component(id, Walking::default());
-
-    // Bathroom door blocker
-    let id = ecs.add_entity();
-    ecs.add_component(id, Name("bathroom::door::blocker".to_string()));
-    ecs.add_component(id, Position(WorldPos::new("bathroom", 4.5, 8.)));
-    ecs.add_component(id, Collision { hitbox: Vec2::new(1., 2.), solid: true });
+    // For the ambient sfx of maps
+    ecs.add_component(id, SfxEmitter::default());
 
     // Bathroom entrance blocker
     let id = ecs.add_entity();
-    ecs.add_component(id, Name("hallway::bathroom_entrance_blocker".to_string()));
+    ecs.add_component(id, Name::new("hallway::bathroom_entrance_blocker"));
     ecs.add_component(id, Position(WorldPos::new("hallway", 3.5, 2.5)));
     ecs.add_component(id, Collision { hitbox: Vec2::new(1., 1.), solid: false });
 
     // Bakery entrance blocker
     let id = ecs.add_entity();
-    ecs.add_component(id, Name("hallway::bakery_entrance_blocker".to_string()));
+    ecs.add_component(id, Name::new("hallway::bakery_entrance_blocker"));
     ecs.add_component(id, Position(WorldPos::new("hallway", 9.5, 2.5)));
     ecs.add_component(id, Collision { hitbox: Vec2::new(1., 1.), solid: false });
 
     // Janitor extension
-    let id = ecs.query_one_with_name::<EntityId>("janitor").unwrap();
+    let id = ecs.find_entity("janitor").unwrap();
     ecs.add_component(
         id,
         InteractionTrigger {
@@ -132,7 +87,7 @@ pub fn load_entities_from_source(ecs: &mut Ecs) {
     );
 
     // School kid extension
-    let id = ecs.query_one_with_name::<EntityId>("school_kid").unwrap();
+    let id = ecs.find_entity("school_kid").unwrap();
     ecs.add_component(
         id,
         InteractionTrigger {
@@ -145,7 +100,7 @@ pub fn load_entities_from_source(ecs: &mut Ecs) {
     );
 
     // Bakery girl extension
-    let id = ecs.query_one_with_name::<EntityId>("bakery_girl").unwrap();
+    let id = ecs.find_entity("bakery_girl").unwrap();
     ecs.add_component(id, Velocity::default());
     ecs.add_component(
         id,
@@ -3,6 +3,7 @@ use crate::dev_ui::console::ConsoleWindow;
 use crate::dev_ui::entities::{EntitiesListWindow, EntityWindow};
 use crate::ecs::{Ecs, EntityId};
 use crate::misc::{LOGGER, StoryVars};
+use crate::schedule::Scheduler;
 use crate::script::{ScriptInstanceId, ScriptManager};
 use egui::text::LayoutJob;
 use egui::{
@@ -28,6 +29,7 @@ pub struct DevUi<'window> {
     scripts_list_window: ScriptsListWindow,
     script_windows: HashMap<ScriptInstanceId, ScriptWindow>,
     story_vars_window: StoryVarsWindow,
+    systems_window: SystemsWindow,
     console_window: ConsoleWindow,
     log_window: LogWindow,
 }
@@ -56,6 +58,7 @@ impl<'window> DevUi<'window> {
             scripts_list_window: ScriptsListWindow::new(),
             script_windows: HashMap::new(),
             story_vars_window: StoryVarsWindow::new(),
+            systems_window: SystemsWindow::new(),
             console_window: ConsoleWindow::new(),
             log_window: LogWindow::new(),
         }
@@ -69,9 +72,9 @@ impl DevUi<'_> {
         start_time: &Instant,
         frame_duration: f32,
         ecs: &mut Ecs,
-        story_vars: &mut StoryVars,
         script_manager: &ScriptManager,
         console: &mut ConsoleCommandExecutor,
+        scheduler: &mut Scheduler,
     ) {
         if !self.open {
             return;
@@ -109,6 +112,7 @@ impl DevUi<'_> {
                     ui.toggle_value(&mut self.entities_list_window.open, "Entities");
                     ui.toggle_value(&mut self.story_vars_window.open, "Story Vars");
                     ui.toggle_value(&mut self.scripts_list_window.open, "Scripts");
+                    ui.toggle_value(&mut self.systems_window.open, "Systems");
                     ui.toggle_value(&mut self.log_window.open, "Log");
 
                     ui.allocate_space([ui.available_width(), 0.].into());
@@ -124,7 +128,8 @@ impl DevUi<'_> {
             for window in self.script_windows.values_mut() {
                 window.show(ctx, script_manager);
             }
-            self.story_vars_window.show(ctx, story_vars);
+            self.story_vars_window.show(ctx, &mut ecs.resource_mut::<StoryVars>());
+            self.systems_window.show(ctx, scheduler);
             self.log_window.show(ctx);
         });
 
@@ -349,6 +354,40 @@ impl StoryVarsWindow {
 // --------------------------------------------------------
 // --------------------------------------------------------
 
+struct SystemsWindow {
+    open: bool,
+}
+
+impl SystemsWindow {
+    fn new() -> Self {
+        Self { open: false }
+    }
+
+    fn show(&mut self, ctx: &Context, scheduler: &mut Scheduler) {
+        if !self.open {
+            return;
+        }
+
+        Window::new("Systems").open(&mut self.open).default_width(250.).show(ctx, |ui| {
+            ScrollArea::vertical().show(ui, |ui| {
+                Grid::new("grid").striped(true).show(ui, |ui| {
+                    for system in scheduler.systems_mut() {
+                        ui.checkbox(&mut system.enabled, system.name);
+                        ui.label(f!("{:?}", system.stage));
+                        ui.label(f!("{:.3}ms", system.duration.as_secs_f64() * 1000.));
+                        ui.end_row();
+                    }
+                });
+
+                ui.allocate_space([ui.available_width(), 0.].into());
+            });
+        });
+    }
+}
+
+// --------------------------------------------------------
+// --------------------------------------------------------
+
 struct LogWindow {
     open: bool,
 }
@@ -1,9 +1,7 @@
-use crate::components::*;
-use crate::ecs::{Component, Ecs, EntityId};
+use crate::components::Name;
+use crate::ecs::{Component, ComponentRegistration, Ecs, EntityId};
 use egui::{Context, ScrollArea, TextEdit, Ui, Window};
 use itertools::Itertools;
-use serde::Serialize;
-use serde::de::DeserializeOwned;
 use std::collections::HashMap;
 use std::format as f;
 use tap::TapFallible;
@@ -56,33 +54,23 @@ pub struct EntityWindow {
     window_id: egui::Id,
     entity_id: EntityId,
     name: Option<String>,
-    component_collapsibles: Vec<Box<dyn CcTrait>>,
+    component_collapsibles: Vec<ComponentCollapsible>,
 }
 
 impl EntityWindow {
     pub fn new(entity_id: EntityId, ecs: &Ecs) -> Self {
         // Name is expected to be immutable, so we only have to set it once
-        let name = ecs.query_one_with_id::<&Name>(entity_id).map(|n| n.0.clone());
+        let name = ecs.query_one_with_id::<&Name>(entity_id).map(|n| n.to_string());
 
         let window_id = egui::Id::new(f!("entity {entity_id:?}"));
 
-        let ccs: Vec<Box<dyn CcTrait>> = vec![
-            Box::new(ComponentCollapsible::<Position>::new(entity_id)),
-            Box::new(ComponentCollapsible::<Collision>::new(entity_id)),
-            Box::new(ComponentCollapsible::<Velocity>::new(entity_id)),
-            Box::new(ComponentCollapsible::<Facing>::new(entity_id)),
-            Box::new(ComponentCollapsible::<Camera>::new(entity_id)),
-            Box::new(ComponentCollapsible::<SpriteComp>::new(entity_id)),
-            Box::new(ComponentCollapsible::<AnimationComp>::new(entity_id)),
-            Box::new(ComponentCollapsible::<CharacterAnims>::new(entity_id)),
-            Box::new(ComponentCollapsible::<DualStateAnims>::new(entity_id)),
-            Box::new(ComponentCollapsible::<NamedAnims>::new(entity_id)),
-            Box::new(ComponentCollapsible::<Walking>::new(entity_id)),
-            Box::new(ComponentCollapsible::<SfxEmitter>::new(entity_id)),
-            Box::new(ComponentCollapsible::<InteractionTrigger>::new(entity_id)),
-            Box::new(ComponentCollapsible::<CollisionTrigger>::new(entity_id)),
-            Box::new(ComponentCollapsible::<AreaTrigger>::new(entity_id)),
-        ];
+        // (Name is shown as the window title instead)
+        let ccs = ecs
+            .component_registry()
+            .iter()
+            .filter(|r| r.name != Name::name())
+            .map(|r| ComponentCollapsible::new(entity_id, *r))
+            .collect();
 
         Self { open: false, window_id, entity_id, name, component_collapsibles: ccs }
     }
@@ -117,54 +105,30 @@ impl EntityWindow {
     }
 }
 
-struct ComponentCollapsible<C> {
+struct ComponentCollapsible {
     entity_id: EntityId,
+    registration: ComponentRegistration,
     text: String,
     is_being_edited: bool,
-    _component: std::marker::PhantomData<C>,
 }
 
-impl<C> ComponentCollapsible<C>
-where
-    C: Component + Serialize + 'static,
-{
-    fn new(entity_id: EntityId) -> Self {
-        Self {
-            entity_id,
-            text: String::new(),
-            is_being_edited: false,
-            _component: std::marker::PhantomData::<C>,
-        }
+impl ComponentCollapsible {
+    fn new(entity_id: EntityId, registration: ComponentRegistration) -> Self {
+        Self { entity_id, registration, text: String::new(), is_being_edited: false }
     }
-}
-
-// I'd like to rename tne generic struct and the trait, but idk what to call them
-trait CcTrait {
-    fn show(&mut self, ui: &mut Ui, ecs: &mut Ecs);
-}
 
-impl<C> CcTrait for ComponentCollapsible<C>
-where
-    C: Component + Serialize + DeserializeOwned + 'static,
-{
     fn show(&mut self, ui: &mut Ui, ecs: &mut Ecs) {
-        let component = ecs.query_one_with_id::<&C>(self.entity_id);
-        if component.is_none() {
+        let Some(value) = (self.registration.save_to_value)(ecs, self.entity_id) else {
             self.text.clear();
             self.is_being_edited = false;
             return;
-        }
+        };
 
         if !self.is_being_edited {
-            self.text = component
-                .as_deref()
-                .map(|c| serde_json::to_string_pretty(c).expect("is serde"))
-                .expect("early return if none");
+            self.text = serde_json::to_string_pretty(&value).expect("is serde");
         }
 
-        drop(component);
-
-        ui.collapsing(C::name(), |ui| {
+        ui.collapsing(self.registration.name, |ui| {
             ui.add(
                 TextEdit::multiline(&mut self.text)
                     .code_editor()
@@ -179,10 +143,13 @@ where
                     }
 
                     if ui.button("Save").clicked() {
-                        if let Ok(component) = serde_json::from_str::<C>(&self.text)
+                        if let Ok(value) = serde_json::from_str::<serde_json::Value>(&self.text)
                             .tap_err(|e| log::error!("Couldn't edit component (err: {e})"))
                         {
-                            ecs.add_component(self.entity_id, component);
+                            (self.registration.add_from_value)(ecs, self.entity_id, &value)
+                                .unwrap_or_else(|e| {
+                                    log::error!("Couldn't edit component (err: {e})")
+                                });
                         }
 
                         self.is_being_edited = false;
@@ -0,0 +1,92 @@
+// Benchmarks of the queries the update systems run every frame
+// (Run with `cargo +nightly bench`)
+extern crate test;
+
+use super::{Changed, Ecs, EntityId, Or};
+use crate::components::{
+    AnimationComp, CharacterAnims, Collision, Facing, Name, Position, Velocity, Walking,
+};
+use crate::world::WorldPos;
+use std::format as f;
+use test::Bencher;
+
+// Roughly the shape of a busy map: every entity has a position, most collide, fewer move, and
+// only a handful are animated characters
+const ENTITY_COUNT: usize = 5000;
+
+fn populated_ecs() -> Ecs {
+    let mut ecs = Ecs::new();
+    for i in 0..ENTITY_COUNT {
+        let id = ecs.add_entity();
+        ecs.add_component(id, Name::new(f!("entity_{i}")));
+        ecs.add_component(id, Position(WorldPos::new("map", i as f64, i as f64)));
+        if i % 4 != 0 {
+            ecs.add_component(id, Collision::default());
+        }
+        if i % 8 == 0 {
+            ecs.add_component(id, Velocity::default());
+            ecs.add_component(id, Walking::default());
+        }
+        if i % 50 == 0 {
+            ecs.add_component(id, AnimationComp::default());
+            ecs.add_component(id, Facing::default());
+            ecs.add_component(
+                id,
+                CharacterAnims {
+                    up: Default::default(),
+                    down: Default::default(),
+                    left: Default::default(),
+                    right: Default::default(),
+                },
+            );
+        }
+    }
+    ecs
+}
+
+#[bench]
+fn position_velocity(b: &mut Bencher) {
+    let ecs = populated_ecs();
+    b.iter(|| {
+        for (mut position, velocity) in ecs.query::<(&mut Position, &Velocity)>() {
+            position.map_pos += velocity.0;
+        }
+    });
+}
+
+#[bench]
+fn moving_collisions(b: &mut Bencher) {
+    let ecs = populated_ecs();
+    b.iter(|| {
+        for (id, position, _, _) in ecs.query::<(EntityId, &mut Position, &Collision, &Velocity)>()
+        {
+            test::black_box((id, &position.map_pos));
+        }
+    });
+}
+
+#[bench]
+fn collisions_except(b: &mut Bencher) {
+    let mut ecs = populated_ecs();
+    let id = ecs.add_entity();
+    b.iter(|| ecs.query_except::<(&Position, &Collision)>(id).count());
+}
+
+#[bench]
+fn changed_animations(b: &mut Bencher) {
+    type AnyChanged =
+        Or<(Changed<AnimationComp>, Changed<CharacterAnims>, Changed<Facing>, Changed<Walking>)>;
+
+    let mut ecs = populated_ecs();
+    b.iter(|| {
+        ecs.begin_system(0);
+        ecs.query::<(&mut AnimationComp, &CharacterAnims, &Facing, &Walking, AnyChanged)>().count()
+    });
+}
+
+#[bench]
+fn query_one_by_name(b: &mut Bencher) {
+    let ecs = populated_ecs();
+    let name = f!("entity_{}", ENTITY_COUNT / 2);
+    b.iter(|| ecs.query_one::<&Position>(name.as_str()).is_some());
+}
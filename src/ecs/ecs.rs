@@ -1,15 +1,16 @@
-use super::query::Query;
-use crate::components::*;
+use super::query::{Query, QueryIter};
+use super::registry::ComponentRegistry;
+use super::storage::{ComponentCell, SparseSet};
+use crate::components::{self, Name, Parent};
+use crate::events;
 use anyhow::anyhow;
 use anymap::AnyMap;
-use serde::Serialize;
-use slotmap::{Key, SecondaryMap, SlotMap, new_key_type};
-use std::cell::RefCell;
-
-// TODO EntityIdentifier enum that can be Name(String) or Id(EntityId)
-// String and EntityId implement Into<{Identifier}>
-// query_one can take Into<{Identifier}>
-// camera target can be an Option<{Identifier}>, etc
+use serde::{Deserialize, Serialize};
+use slotmap::{Key, SlotMap, new_key_type};
+use std::any::{Any, TypeId};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
 
 pub trait Component {
     // Unique name of the component
@@ -17,11 +18,59 @@ pub trait Component {
     fn name() -> &'static str {
         std::any::type_name::<Self>().split("::").last().expect("split always returns at least 1")
     }
+
+    // Called when the component is removed from its entity, is replaced by another component of
+    // the same type, or its entity is removed
+    fn on_remove(&mut self) {}
 }
 
 new_key_type! { pub struct EntityId; }
 new_key_type! { pub struct DeferredEntityId; }
 
+// Refers to an entity either by its Name or by its EntityId
+// Names are what scripts and data files usually use, but unnamed entities can only be referred to
+// by id
+// (Untagged so that a plain string in JSON is a name)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EntityIdentifier {
+    Name(String),
+    Id(EntityId),
+}
+
+impl From<&str> for EntityIdentifier {
+    fn from(name: &str) -> Self {
+        Self::Name(name.to_string())
+    }
+}
+
+impl From<String> for EntityIdentifier {
+    fn from(name: String) -> Self {
+        Self::Name(name)
+    }
+}
+
+impl From<EntityId> for EntityIdentifier {
+    fn from(id: EntityId) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl From<&EntityIdentifier> for EntityIdentifier {
+    fn from(identifier: &EntityIdentifier) -> Self {
+        identifier.clone()
+    }
+}
+
+impl Display for EntityIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Id(id) => write!(f, "{id:?}"),
+        }
+    }
+}
+
 pub enum RealOrDeferredEntityId {
     Real(EntityId),
     Deferred(DeferredEntityId),
@@ -39,50 +88,72 @@ impl From<DeferredEntityId> for RealOrDeferredEntityId {
     }
 }
 
-type QueryResultIter<'r, Q> = Box<dyn Iterator<Item = <Q as Query>::Result<'r>> + 'r>;
-pub type ComponentMap<C> = SecondaryMap<EntityId, RefCell<C>>;
-
 pub struct Ecs {
     // TODO implement slotmap myself so that I can control its serde functionality
     pub entity_ids: SlotMap<EntityId, ()>,
-    component_maps: AnyMap,
+    // A SparseSet for every component type that has ever been added
+    storages: AnyMap,
+    // Typed singletons for global state (story vars, ui data, audio, etc)
+    // Borrowed at runtime like components, so they can be borrowed mutably from a shared ecs
+    resources: AnyMap,
     deferred_mutations: RefCell<Vec<Box<dyn FnOnce(&mut Ecs)>>>,
     deferred_entity_ids: RefCell<SlotMap<DeferredEntityId, EntityId>>,
+    component_registry: ComponentRegistry,
+    // Index of Name components, kept up to date by add_component and remove_component
+    // (Which is why a Name's field is private, so that it can't be changed through a query)
+    names: HashMap<String, EntityId>,
+    // A remover for every component type that has ever been added, so that remove_entity can
+    // remove every component of an entity without knowing their types
+    component_removers: HashMap<TypeId, fn(&mut Ecs, EntityId)>,
+    // A queue for every event type, and a function for updating each one
+    pub(super) events: AnyMap,
+    pub(super) event_updaters: Vec<fn(&mut Ecs)>,
+    // Number of events ever sent (of any type), which indexes each event
+    pub(super) sent_event_count: Cell<u64>,
+    // For Changed and Added queries
+    // Advanced every time a system runs (and once after all have run), and last_change_tick is set
+    // to the tick when the running system last ran
+    change_tick: u32,
+    last_change_tick: u32,
 }
 
 impl Ecs {
     pub fn new() -> Self {
-        Self {
+        let mut component_registry = ComponentRegistry::new();
+        components::register_components(&mut component_registry);
+
+        let mut ecs = Self {
             entity_ids: SlotMap::with_key(),
-            component_maps: AnyMap::new(),
+            storages: AnyMap::new(),
+            resources: AnyMap::new(),
             deferred_mutations: RefCell::new(Vec::new()),
             deferred_entity_ids: RefCell::new(SlotMap::with_key()),
-        }
-    }
-
-    fn filter<'ecs, Q>(&'ecs self) -> Box<dyn Iterator<Item = EntityId> + 'ecs>
-    where
-        Q: Query,
-    {
-        Box::new(self.entity_ids.keys().filter(|id| Q::filter(*id, &self.component_maps)))
+            component_registry,
+            names: HashMap::new(),
+            component_removers: HashMap::new(),
+            events: AnyMap::new(),
+            event_updaters: Vec::new(),
+            sent_event_count: Cell::new(0),
+            // (Starts at 1 so that components added before any system has run count as added)
+            change_tick: 1,
+            last_change_tick: 0,
+        };
+        events::add_events(&mut ecs);
+        ecs
     }
 
-    pub fn query<'ecs, Q>(&'ecs self) -> QueryResultIter<'ecs, Q>
+    pub fn query<Q>(&self) -> QueryIter<'_, Q>
     where
         Q: Query,
     {
-        Box::new(self.filter::<Q>().map(|id| Q::borrow(id, &self.component_maps)))
+        QueryIter::new(self, None)
     }
 
-    pub fn query_except<'ecs, Q>(&'ecs self, except: EntityId) -> QueryResultIter<'ecs, Q>
+    pub fn query_except<Q>(&self, except: EntityId) -> QueryIter<'_, Q>
     where
         Q: Query,
     {
-        Box::new(
-            self.filter::<Q>()
-                .filter(move |id| *id != except)
-                .map(|id| Q::borrow(id, &self.component_maps)),
-        )
+        QueryIter::new(self, Some(except))
     }
 
     // DOES filter in a way that avoids double borrow in a nested query
@@ -91,38 +162,126 @@ impl Ecs {
     where
         Q: Query,
     {
-        Some(id)
-            .filter(|id| Q::filter(*id, &self.component_maps))
-            .map(|id| Q::borrow(id, &self.component_maps))
+        if !self.entity_ids.contains_key(id) {
+            return None;
+        }
+        Q::fetch(self).filter(|fetch| Q::matches(*fetch, id)).map(|fetch| Q::get(fetch, id))
     }
 
-    // Does NOT filter in a way that avoids double borrow in a nested query
-    // (Because it filters by name during the query)
-    pub fn query_one_with_name<'ecs, Q>(&'ecs self, name: &str) -> Option<Q::Result<'ecs>>
+    // Also avoids double borrow in a nested query
+    // (Because it resolves the identifier to an id first, then runs the query)
+    pub fn query_one<'ecs, Q>(
+        &'ecs self,
+        identifier: impl Into<EntityIdentifier>,
+    ) -> Option<Q::Result<'ecs>>
     where
-        Q: Query + 'static,
+        Q: Query,
     {
-        self.query::<(&Name, Q)>().find(|(n, _)| n.as_str() == name).map(|(_, q)| q)
+        self.find_entity(identifier).and_then(|id| self.query_one_with_id::<Q>(id))
+    }
+
+    // Get the id of an existing entity
+    pub fn find_entity(&self, identifier: impl Into<EntityIdentifier>) -> Option<EntityId> {
+        match identifier.into() {
+            EntityIdentifier::Name(name) => self.names.get(&name).copied(),
+            EntityIdentifier::Id(id) => Some(id).filter(|id| self.entity_ids.contains_key(*id)),
+        }
     }
 
     pub fn add_entity(&mut self) -> EntityId {
         self.entity_ids.insert(())
     }
 
+    // Removes the entity and all of its descendants, removing every component of each first
+    // (running their on_remove hooks)
     pub fn remove_entity(&mut self, entity_id: EntityId) {
-        self.entity_ids.remove(entity_id);
+        // Descendants are found through Parent rather than Children, since Children is only
+        // updated once per frame and might be missing children added since
+        let mut children_of: HashMap<EntityId, Vec<EntityId>> = HashMap::new();
+        for (id, parent) in self.query::<(EntityId, &Parent)>() {
+            if let Some(parent_id) = self.find_entity(&parent.entity) {
+                children_of.entry(parent_id).or_default().push(id);
+            }
+        }
+
+        // (Checking what's already found also stops at cycles)
+        let mut to_remove = vec![entity_id];
+        let mut found = HashSet::from([entity_id]);
+        let mut i = 0;
+        while let Some(parent_id) = to_remove.get(i).copied() {
+            for child_id in children_of.get(&parent_id).into_iter().flatten() {
+                if found.insert(*child_id) {
+                    to_remove.push(*child_id);
+                }
+            }
+            i += 1;
+        }
+
+        let removers: Vec<_> = self.component_removers.values().copied().collect();
+        for id in to_remove {
+            for remove in &removers {
+                remove(self, id);
+            }
+            self.entity_ids.remove(id);
+        }
+    }
+
+    pub fn remove_all_entities(&mut self) {
+        // (Every descendant goes too, so there's no need to find them)
+        let ids: Vec<_> = self.entity_ids.keys().collect();
+        let removers: Vec<_> = self.component_removers.values().copied().collect();
+        for id in ids {
+            for remove in &removers {
+                remove(self, id);
+            }
+            self.entity_ids.remove(id);
+        }
     }
 
     pub fn add_component<C>(&mut self, entity_id: EntityId, component: C)
     where
         C: Component + 'static,
     {
-        if let Some(cm) = self.component_maps.get_mut::<ComponentMap<C>>() {
-            cm.insert(entity_id, RefCell::new(component));
+        // (Such as a stale id kept by a script, since nothing would ever remove the component)
+        if !self.entity_ids.contains_key(entity_id) {
+            log::error!("Entity doesn't exist, component not added: {}", C::name());
+            return;
+        }
+
+        if let Some(name) = (&component as &dyn Any).downcast_ref::<Name>() {
+            match self.names.get(name.as_str()) {
+                Some(other_id) if *other_id != entity_id => {
+                    log::error!("Name already in use, not added: {}", name.as_str());
+                    return;
+                }
+                _ => {
+                    self.remove_from_name_index(entity_id);
+                    self.names.insert(name.to_string(), entity_id);
+                }
+            }
+        }
+
+        // Replacing a component counts as a change, not an addition
+        let added_tick = self
+            .storage::<C>()
+            .and_then(|s| s.get(entity_id))
+            .map_or(self.change_tick, |c| c.added_tick);
+        let cell = ComponentCell {
+            component: RefCell::new(component),
+            added_tick,
+            changed_tick: Cell::new(self.change_tick),
+        };
+
+        if let Some(storage) = self.storages.get_mut::<SparseSet<C>>() {
+            if let Some(old) = storage.insert(entity_id, cell) {
+                old.component.into_inner().on_remove();
+            }
         } else {
-            let mut cm = SecondaryMap::<EntityId, RefCell<C>>::new();
-            cm.insert(entity_id, RefCell::new(component));
-            self.component_maps.insert(cm);
+            let mut storage = SparseSet::<C>::new();
+            storage.insert(entity_id, cell);
+            self.storages.insert(storage);
+            self.component_removers
+                .insert(TypeId::of::<C>(), |ecs, id| ecs.remove_component::<C>(id));
         }
     }
 
@@ -130,7 +289,87 @@ impl Ecs {
     where
         C: Component + 'static,
     {
-        self.component_maps.get_mut::<ComponentMap<C>>().map(|cm| cm.remove(entity_id));
+        if TypeId::of::<C>() == TypeId::of::<Name>() {
+            self.remove_from_name_index(entity_id);
+        }
+
+        if let Some(old) = self.storages.get_mut::<SparseSet<C>>().and_then(|s| s.remove(entity_id))
+        {
+            old.component.into_inner().on_remove();
+        }
+    }
+
+    // Replaces the resource if it already exists
+    pub fn insert_resource<R>(&mut self, resource: R)
+    where
+        R: 'static,
+    {
+        self.resources.insert(RefCell::new(resource));
+    }
+
+    // Panics if the resource doesn't exist (resources are all inserted at startup)
+    pub fn resource<R>(&self) -> Ref<'_, R>
+    where
+        R: 'static,
+    {
+        self.resource_cell::<R>().borrow()
+    }
+
+    // Panics if the resource doesn't exist (resources are all inserted at startup)
+    pub fn resource_mut<R>(&self) -> RefMut<'_, R>
+    where
+        R: 'static,
+    {
+        self.resource_cell::<R>().borrow_mut()
+    }
+
+    fn resource_cell<R>(&self) -> &RefCell<R>
+    where
+        R: 'static,
+    {
+        self.resources
+            .get::<RefCell<R>>()
+            .unwrap_or_else(|| panic!("resource doesn't exist: {}", std::any::type_name::<R>()))
+    }
+
+    // For replacing the whole ecs while keeping its global state
+    pub fn take_resources_from(&mut self, other: &mut Ecs) {
+        self.resources = std::mem::replace(&mut other.resources, AnyMap::new());
+    }
+
+    pub(super) fn storage<C>(&self) -> Option<&SparseSet<C>>
+    where
+        C: Component + 'static,
+    {
+        self.storages.get::<SparseSet<C>>()
+    }
+
+    pub fn change_tick(&self) -> u32 {
+        self.change_tick
+    }
+
+    pub(super) fn last_change_tick(&self) -> u32 {
+        self.last_change_tick
+    }
+
+    // Call right before running a system, with the change tick from when it last ran
+    pub fn begin_system(&mut self, last_run_tick: u32) {
+        // (A last run tick from the future means the ecs was replaced, such as by loading a save,
+        // so everything counts as changed)
+        self.last_change_tick = if last_run_tick <= self.change_tick { last_run_tick } else { 0 };
+        self.change_tick += 1;
+    }
+
+    // Call after running all systems, so that changes made outside of systems get a tick of their
+    // own that every system sees, including the last one that ran
+    pub fn end_systems(&mut self) {
+        self.change_tick += 1;
+    }
+
+    fn remove_from_name_index(&mut self, entity_id: EntityId) {
+        if let Some(name) = self.query_one_with_id::<&Name>(entity_id).map(|n| n.to_string()) {
+            self.names.remove(&name);
+        }
     }
 
     // NOW explain all of this deferred operations code, cause it's confusing af
@@ -197,7 +436,9 @@ impl Ecs {
         self.deferred_entity_ids.borrow_mut().clear();
     }
 
-    // TODO single match-on-name func that takes callback?
+    pub fn component_registry(&self) -> &ComponentRegistry {
+        &self.component_registry
+    }
 
     pub fn add_component_with_name(
         &mut self,
@@ -205,31 +446,21 @@ impl Ecs {
         component_name: &str,
         data: &serde_json::Value,
     ) -> anyhow::Result<()> {
-        use serde_json::from_value as sjfv;
-        let data_c = data.clone();
-
-        let r: anyhow::Result<()> = try {
-            match component_name {
-                "Name" => self.add_component(id, sjfv::<Name>(data_c)?),
-                "Position" => self.add_component(id, sjfv::<Position>(data_c)?),
-                "Velocity" => self.add_component(id, sjfv::<Velocity>(data_c)?),
-                "Collision" => self.add_component(id, sjfv::<Collision>(data_c)?),
-                "SfxEmitter" => self.add_component(id, sjfv::<SfxEmitter>(data_c)?),
-                "SpriteComp" => self.add_component(id, sjfv::<SpriteComp>(data_c)?),
-                "Facing" => self.add_component(id, sjfv::<Facing>(data_c)?),
-                "Walking" => self.add_component(id, sjfv::<Walking>(data_c)?),
-                "Camera" => self.add_component(id, sjfv::<Camera>(data_c)?),
-                "AnimationComp" => self.add_component(id, sjfv::<AnimationComp>(data_c)?),
-                "CharacterAnims" => self.add_component(id, sjfv::<CharacterAnims>(data_c)?),
-                "DualStateAnims" => self.add_component(id, sjfv::<DualStateAnims>(data_c)?),
-                "NamedAnims" => self.add_component(id, sjfv::<NamedAnims>(data_c)?),
-                "InteractionTrigger" => self.add_component(id, sjfv::<InteractionTrigger>(data_c)?),
-                "CollisionTrigger" => self.add_component(id, sjfv::<CollisionTrigger>(data_c)?),
-                "AreaTrigger" => self.add_component(id, sjfv::<AreaTrigger>(data_c)?),
-                _ => Err(anyhow!("invalid component name `{component_name}`"))?,
-            }
-        };
-        r.map_err(|e| {
+        let add_from_value = self
+            .component_registry
+            .get(component_name)
+            .ok_or(anyhow!("invalid component name `{component_name}`"))?
+            .add_from_value;
+
+        // Report duplicate names to the caller rather than only logging them in add_component
+        if component_name == Name::name()
+            && let Some(other_id) = data.as_str().and_then(|n| self.names.get(n))
+            && *other_id != id
+        {
+            return Err(anyhow!("name already in use `{data}`"));
+        }
+
+        add_from_value(self, id, data).map_err(|e| {
             anyhow!(
                 "invalid json component\nname: {component_name}\ndata: {}\nerr: {e}",
                 serde_json::to_string_pretty(&data).expect("is serde")
@@ -242,25 +473,13 @@ impl Ecs {
         id: EntityId,
         component_name: &str,
     ) -> anyhow::Result<()> {
-        match component_name {
-            "Name" => self.remove_component::<Name>(id),
-            "Position" => self.remove_component::<Position>(id),
-            "Velocity" => self.remove_component::<Velocity>(id),
-            "Collision" => self.remove_component::<Collision>(id),
-            "SfxEmitter" => self.remove_component::<SfxEmitter>(id),
-            "SpriteComp" => self.remove_component::<SpriteComp>(id),
-            "Facing" => self.remove_component::<Facing>(id),
-            "Walking" => self.remove_component::<Walking>(id),
-            "Camera" => self.remove_component::<Camera>(id),
-            "AnimationComp" => self.remove_component::<AnimationComp>(id),
-            "CharacterAnims" => self.remove_component::<CharacterAnims>(id),
-            "DualStateAnims" => self.remove_component::<DualStateAnims>(id),
-            "NamedAnims" => self.remove_component::<NamedAnims>(id),
-            "InteractionTrigger" => self.remove_component::<InteractionTrigger>(id),
-            "CollisionTrigger" => self.remove_component::<CollisionTrigger>(id),
-            "AreaTrigger" => self.remove_component::<AreaTrigger>(id),
-            _ => return Err(anyhow!("invalid component name `{component_name}`")),
-        }
+        let remove = self
+            .component_registry
+            .get(component_name)
+            .ok_or(anyhow!("invalid component name `{component_name}`"))?
+            .remove;
+
+        remove(self, id);
 
         Ok(())
     }
@@ -268,38 +487,11 @@ impl Ecs {
     // This is only for debug or for easily generating component json
     // It doesn't include an id for restoring game state
     pub fn save_components_to_value(&self, id: EntityId) -> serde_json::Value {
-        fn insert<C>(
-            components: &mut serde_json::Map<String, serde_json::Value>,
-            id: EntityId,
-            ecs: &Ecs,
-        ) where
-            C: Component + Clone + Serialize + 'static,
-        {
-            if let Some(component) = ecs.query_one_with_id::<&C>(id)
-                && let Ok(value) = serde_json::to_value(component.clone())
-            {
-                components.insert(C::name().to_string(), value);
-            }
-        }
-
-        let mut components = serde_json::Map::new();
-
-        insert::<Name>(&mut components, id, self);
-        insert::<Position>(&mut components, id, self);
-        insert::<Velocity>(&mut components, id, self);
-        insert::<Collision>(&mut components, id, self);
-        insert::<SfxEmitter>(&mut components, id, self);
-        insert::<SpriteComp>(&mut components, id, self);
-        insert::<Facing>(&mut components, id, self);
-        insert::<Walking>(&mut components, id, self);
-        insert::<Camera>(&mut components, id, self);
-        insert::<AnimationComp>(&mut components, id, self);
-        insert::<CharacterAnims>(&mut components, id, self);
-        insert::<DualStateAnims>(&mut components, id, self);
-        insert::<NamedAnims>(&mut components, id, self);
-        insert::<InteractionTrigger>(&mut components, id, self);
-        insert::<CollisionTrigger>(&mut components, id, self);
-        insert::<AreaTrigger>(&mut components, id, self);
+        let components = self
+            .component_registry
+            .iter()
+            .filter_map(|r| (r.save_to_value)(self, id).map(|v| (r.name.to_string(), v)))
+            .collect();
 
         serde_json::Value::Object(components)
     }
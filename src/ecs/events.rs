@@ -0,0 +1,113 @@
+use super::Ecs;
+use std::cell::RefCell;
+use std::ops::Range;
+
+pub trait Event: Clone + 'static {
+    // Unique name of the event (what Lua subscribes to)
+    // By default, it's the unqualified type name
+    fn name() -> &'static str {
+        std::any::type_name::<Self>().split("::").last().expect("split always returns at least 1")
+    }
+}
+
+// The queue of one event type
+// Every event is tagged with the change tick it was sent at, so that a system reads exactly the
+// events sent since it last ran (like Changed)
+// And with its index among all sent events, for readers that keep their own cursor
+pub(super) struct Events<E> {
+    events: Vec<(u32, u64, E)>,
+    // The change tick at the previous update
+    last_update_tick: u32,
+}
+
+impl<E> Events<E> {
+    pub(super) fn new() -> Self {
+        Self { events: Vec::new(), last_update_tick: 0 }
+    }
+}
+
+impl Ecs {
+    // Every event type must be added once before it can be sent or read
+    pub fn add_event<E>(&mut self)
+    where
+        E: Event,
+    {
+        if self.events.contains::<RefCell<Events<E>>>() {
+            log::error!("Event added more than once: {}", E::name());
+            return;
+        }
+
+        self.events.insert(RefCell::new(Events::<E>::new()));
+        self.event_updaters.push(|ecs| {
+            let change_tick = ecs.change_tick();
+            let mut events = ecs.events::<E>().borrow_mut();
+            // Events survive two updates, so that every system runs once while they exist,
+            // whether it runs before or after the sender
+            let last_update_tick = events.last_update_tick;
+            events.events.retain(|(tick, _, _)| *tick > last_update_tick);
+            events.last_update_tick = change_tick;
+        });
+    }
+
+    pub fn send_event<E>(&self, event: E)
+    where
+        E: Event,
+    {
+        let index = self.sent_event_count.get();
+        self.sent_event_count.set(index + 1);
+        self.events::<E>().borrow_mut().events.push((self.change_tick(), index, event));
+    }
+
+    // The events sent since the running system last ran
+    // (A system does not read its own events)
+    pub fn read_events<E>(&self) -> Vec<E>
+    where
+        E: Event,
+    {
+        let last_change_tick = self.last_change_tick();
+        self.events::<E>()
+            .borrow()
+            .events
+            .iter()
+            .filter(|(tick, _, _)| *tick > last_change_tick)
+            .map(|(_, _, event)| event.clone())
+            .collect()
+    }
+
+    // The index that the next sent event will have
+    // For readers that run several times within one system (such as scripts, which all run in
+    // update_scripts), and so can't rely on when the system last ran
+    pub fn event_cursor(&self) -> u64 {
+        self.sent_event_count.get()
+    }
+
+    // The events sent between two cursors (that still exist)
+    pub fn read_events_between<E>(&self, cursors: Range<u64>) -> Vec<E>
+    where
+        E: Event,
+    {
+        self.events::<E>()
+            .borrow()
+            .events
+            .iter()
+            .filter(|(_, index, _)| cursors.contains(index))
+            .map(|(_, _, event)| event.clone())
+            .collect()
+    }
+
+    // Call once per frame
+    pub fn update_events(&mut self) {
+        for update in self.event_updaters.clone() {
+            update(self);
+        }
+    }
+
+    fn events<E>(&self) -> &RefCell<Events<E>>
+    where
+        E: Event,
+    {
+        self.events
+            .get::<RefCell<Events<E>>>()
+            .unwrap_or_else(|| panic!("event wasn't added: {}", E::name()))
+    }
+}
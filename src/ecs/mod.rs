@@ -1,4 +1,13 @@
+#[cfg(test)]
+mod bench;
 mod query;
+mod registry;
+mod storage;
 
 mod ecs;
+mod events;
 pub use ecs::*;
+pub use events::Event;
+#[allow(unused_imports)]
+pub use query::{Added, Changed, Or, With, Without};
+pub use registry::*;
@@ -1,24 +1,149 @@
-use super::{Component, ComponentMap, EntityId};
-use anymap::AnyMap;
+use super::storage::{ComponentCell, SparseSet};
+use super::{Component, Ecs, EntityId};
+use slotmap::SlotMap;
 use std::cell::{Ref, RefMut};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 
+// A query fetches everything it needs from the ecs once (the storages of its components), then
+// matches and borrows each entity through that, with no further type map lookups
 pub trait Query {
     type Result<'r>;
+    type Fetch<'r>: Copy;
 
-    fn borrow(id: EntityId, component_maps: &AnyMap) -> Self::Result<'_>;
-    fn filter(id: EntityId, component_maps: &AnyMap) -> bool;
+    // None if the query can't match any entity (such as a component that was never added)
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>>;
+    // Ids that every match must be among, if the query requires a component
+    // (The smallest storage, for tuples)
+    fn candidates<'r>(fetch: Self::Fetch<'r>) -> Option<&'r [EntityId]>;
+    fn matches(fetch: Self::Fetch<'_>, id: EntityId) -> bool;
+    // Only valid if matches
+    fn get<'r>(fetch: Self::Fetch<'r>, id: EntityId) -> Self::Result<'r>;
+}
+
+// Filters borrow nothing and have a () result
+// They are meant to go in a tuple with other queries
+pub struct With<C>(PhantomData<C>);
+pub struct Without<C>(PhantomData<C>);
+// Changed and Added match components changed or added since the running system last ran
+// (A system does not see its own changes)
+pub struct Changed<C>(PhantomData<C>);
+#[allow(dead_code)]
+pub struct Added<C>(PhantomData<C>);
+// Matches if any of a tuple of filters matches
+pub struct Or<F>(PhantomData<F>);
+
+// Mutable component borrow that marks the component as changed when it is actually mutated
+// (Merely borrowing a component mutably in a query does not count as a change)
+pub struct Mut<'r, C> {
+    component: RefMut<'r, C>,
+    cell: &'r ComponentCell<C>,
+    change_tick: u32,
+}
+
+impl<C> Deref for Mut<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &Self::Target {
+        &self.component
+    }
+}
+
+impl<C> DerefMut for Mut<'_, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.cell.set_changed(self.change_tick);
+        &mut self.component
+    }
+}
+
+impl<C> Mut<'_, C> {
+    // For bookkeeping that nothing reacts to (such as animation time), so that it doesn't make
+    // the component match Changed every frame
+    pub fn bypass_change_detection(&mut self) -> &mut C {
+        &mut self.component
+    }
+}
+
+// Iterates the query's candidates if it has any, or else every entity
+// (Candidates come from storages, so they're also checked against the live entities, in case a
+// storage still holds a component of a removed entity)
+pub struct QueryIter<'ecs, Q>
+where
+    Q: Query,
+{
+    fetch: Option<Q::Fetch<'ecs>>,
+    ids: QueryIds<'ecs>,
+    entity_ids: &'ecs SlotMap<EntityId, ()>,
+    except: Option<EntityId>,
+}
+
+enum QueryIds<'ecs> {
+    Candidates(std::slice::Iter<'ecs, EntityId>),
+    All(slotmap::basic::Keys<'ecs, EntityId, ()>),
+}
+
+impl<'ecs, Q> QueryIter<'ecs, Q>
+where
+    Q: Query,
+{
+    pub(super) fn new(ecs: &'ecs Ecs, except: Option<EntityId>) -> Self {
+        let fetch = Q::fetch(ecs);
+        let ids = match fetch {
+            Some(fetch) => match Q::candidates(fetch) {
+                Some(ids) => QueryIds::Candidates(ids.iter()),
+                None => QueryIds::All(ecs.entity_ids.keys()),
+            },
+            None => QueryIds::Candidates([].iter()),
+        };
+        Self { fetch, ids, entity_ids: &ecs.entity_ids, except }
+    }
+}
+
+impl<'ecs, Q> Iterator for QueryIter<'ecs, Q>
+where
+    Q: Query,
+{
+    type Item = Q::Result<'ecs>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fetch = self.fetch?;
+        loop {
+            let id = match &mut self.ids {
+                QueryIds::Candidates(ids) => {
+                    let id = *ids.next()?;
+                    if !self.entity_ids.contains_key(id) {
+                        continue;
+                    }
+                    id
+                }
+                QueryIds::All(ids) => ids.next()?,
+            };
+            if Some(id) != self.except && Q::matches(fetch, id) {
+                return Some(Q::get(fetch, id));
+            }
+        }
+    }
 }
 
 impl Query for EntityId {
     type Result<'r> = EntityId;
+    type Fetch<'r> = ();
 
-    fn borrow(id: EntityId, _: &AnyMap) -> Self::Result<'_> {
-        id
+    fn fetch(_: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some(())
     }
 
-    fn filter(_: EntityId, _: &AnyMap) -> bool {
+    fn candidates<'r>(_: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        None
+    }
+
+    fn matches(_: Self::Fetch<'_>, _: EntityId) -> bool {
         true
     }
+
+    fn get<'r>(_: Self::Fetch<'r>, id: EntityId) -> Self::Result<'r> {
+        id
+    }
 }
 
 impl<C> Query for &C
@@ -26,13 +151,22 @@ where
     C: Component + 'static,
 {
     type Result<'r> = Ref<'r, C>;
+    type Fetch<'r> = &'r SparseSet<C>;
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        ecs.storage::<C>()
+    }
 
-    fn borrow(id: EntityId, component_maps: &AnyMap) -> Self::Result<'_> {
-        component_maps.get::<ComponentMap<C>>().unwrap().get(id).unwrap().borrow()
+    fn candidates<'r>(storage: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        Some(storage.ids())
     }
 
-    fn filter(id: EntityId, component_maps: &AnyMap) -> bool {
-        component_maps.get::<ComponentMap<C>>().is_some_and(|cm| cm.contains_key(id))
+    fn matches(storage: Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.contains(id)
+    }
+
+    fn get<'r>(storage: Self::Fetch<'r>, id: EntityId) -> Self::Result<'r> {
+        storage.get(id).expect("matched").component.borrow()
     }
 }
 
@@ -40,14 +174,24 @@ impl<C> Query for &mut C
 where
     C: Component + 'static,
 {
-    type Result<'r> = RefMut<'r, C>;
+    type Result<'r> = Mut<'r, C>;
+    type Fetch<'r> = (&'r SparseSet<C>, u32);
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some((ecs.storage::<C>()?, ecs.change_tick()))
+    }
+
+    fn candidates<'r>((storage, _): Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        Some(storage.ids())
+    }
 
-    fn borrow(id: EntityId, component_maps: &AnyMap) -> Self::Result<'_> {
-        component_maps.get::<ComponentMap<C>>().unwrap().get(id).unwrap().borrow_mut()
+    fn matches((storage, _): Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.contains(id)
     }
 
-    fn filter(id: EntityId, component_maps: &AnyMap) -> bool {
-        component_maps.get::<ComponentMap<C>>().is_some_and(|cm| cm.contains_key(id))
+    fn get<'r>((storage, change_tick): Self::Fetch<'r>, id: EntityId) -> Self::Result<'r> {
+        let cell = storage.get(id).expect("matched");
+        Mut { component: cell.component.borrow_mut(), cell, change_tick }
     }
 }
 
@@ -56,48 +200,350 @@ where
     Q: Query + 'static,
 {
     type Result<'r> = Option<Q::Result<'r>>;
+    type Fetch<'r> = Option<Q::Fetch<'r>>;
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some(Q::fetch(ecs))
+    }
 
-    fn borrow(id: EntityId, component_maps: &AnyMap) -> Self::Result<'_> {
-        if Q::filter(id, component_maps) { Some(Q::borrow(id, component_maps)) } else { None }
+    fn candidates<'r>(_: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        None
     }
 
-    fn filter(_: EntityId, _: &AnyMap) -> bool {
+    fn matches(_: Self::Fetch<'_>, _: EntityId) -> bool {
         true
     }
+
+    fn get<'r>(fetch: Self::Fetch<'r>, id: EntityId) -> Self::Result<'r> {
+        fetch.filter(|f| Q::matches(*f, id)).map(|f| Q::get(f, id))
+    }
 }
 
+impl<C> Query for With<C>
+where
+    C: Component + 'static,
+{
+    type Result<'r> = ();
+    type Fetch<'r> = &'r SparseSet<C>;
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        ecs.storage::<C>()
+    }
+
+    fn candidates<'r>(storage: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        Some(storage.ids())
+    }
+
+    fn matches(storage: Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.contains(id)
+    }
+
+    fn get<'r>(_: Self::Fetch<'r>, _: EntityId) -> Self::Result<'r> {}
+}
+
+impl<C> Query for Without<C>
+where
+    C: Component + 'static,
+{
+    type Result<'r> = ();
+    type Fetch<'r> = Option<&'r SparseSet<C>>;
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some(ecs.storage::<C>())
+    }
+
+    fn candidates<'r>(_: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        None
+    }
+
+    fn matches(storage: Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.is_none_or(|s| !s.contains(id))
+    }
+
+    fn get<'r>(_: Self::Fetch<'r>, _: EntityId) -> Self::Result<'r> {}
+}
+
+impl<C> Query for Changed<C>
+where
+    C: Component + 'static,
+{
+    type Result<'r> = ();
+    type Fetch<'r> = (&'r SparseSet<C>, u32);
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some((ecs.storage::<C>()?, ecs.last_change_tick()))
+    }
+
+    fn candidates<'r>((storage, _): Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        Some(storage.ids())
+    }
+
+    fn matches((storage, last_change_tick): Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.get(id).is_some_and(|cell| cell.changed_tick.get() > last_change_tick)
+    }
+
+    fn get<'r>(_: Self::Fetch<'r>, _: EntityId) -> Self::Result<'r> {}
+}
+
+impl<C> Query for Added<C>
+where
+    C: Component + 'static,
+{
+    type Result<'r> = ();
+    type Fetch<'r> = (&'r SparseSet<C>, u32);
+
+    fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+        Some((ecs.storage::<C>()?, ecs.last_change_tick()))
+    }
+
+    fn candidates<'r>((storage, _): Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+        Some(storage.ids())
+    }
+
+    fn matches((storage, last_change_tick): Self::Fetch<'_>, id: EntityId) -> bool {
+        storage.get(id).is_some_and(|cell| cell.added_tick > last_change_tick)
+    }
+
+    fn get<'r>(_: Self::Fetch<'r>, _: EntityId) -> Self::Result<'r> {}
+}
+
+macro_rules! impl_query_for_or_tuple {
+  ($($name:ident)*) => {
+      #[allow(non_snake_case)]
+      impl<$($name,)*> Query for Or<($($name,)*)>
+      where $($name: for<'r> Query<Result<'r> = ()> + 'static,)*
+      {
+          type Result<'r> = ();
+          type Fetch<'r> = ($(Option<$name::Fetch<'r>>,)*);
+
+          fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+              Some(($($name::fetch(ecs),)*))
+          }
+
+          fn candidates<'r>(_: Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+              None
+          }
+
+          fn matches(($($name,)*): Self::Fetch<'_>, id: EntityId) -> bool {
+              $($name.is_some_and(|f| $name::matches(f, id)))||*
+          }
+
+          fn get<'r>(_: Self::Fetch<'r>, _: EntityId) -> Self::Result<'r> {}
+      }
+  };
+}
+
+impl_query_for_or_tuple!(A B);
+impl_query_for_or_tuple!(A B C);
+impl_query_for_or_tuple!(A B C D);
+impl_query_for_or_tuple!(A B C D E);
+
 macro_rules! impl_query_for_tuple {
   ($($name:ident)*) => {
       #[allow(unused)]
+      #[allow(non_snake_case)]
       #[allow(clippy::unused_unit)]
       impl<$($name,)*> Query for ($($name,)*)
       where $($name: Query + 'static,)*
       {
           type Result<'r> = ($($name::Result<'r>,)*);
+          type Fetch<'r> = ($($name::Fetch<'r>,)*);
+
+          fn fetch(ecs: &Ecs) -> Option<Self::Fetch<'_>> {
+              Some(($($name::fetch(ecs)?,)*))
+          }
 
-          fn borrow(id: EntityId, component_maps: &AnyMap) -> Self::Result<'_> {
-              ($($name::borrow(id, component_maps),)*)
+          fn candidates<'r>(($($name,)*): Self::Fetch<'r>) -> Option<&'r [EntityId]> {
+              let candidates: &[Option<&'r [EntityId]>] = &[$($name::candidates($name),)*];
+              candidates.iter().flatten().min_by_key(|ids| ids.len()).copied()
           }
 
-          fn filter(id: EntityId, component_maps: &AnyMap) -> bool {
-              match ($($name::filter(id, component_maps),)*) {
-                  ($(replace_expr!($name true),)*) => true,
-                  _ => false,
-              }
+          fn matches(($($name,)*): Self::Fetch<'_>, id: EntityId) -> bool {
+              true $(&& $name::matches($name, id))*
+          }
+
+          fn get<'r>(($($name,)*): Self::Fetch<'r>, id: EntityId) -> Self::Result<'r> {
+              ($($name::get($name, id),)*)
           }
       }
   };
 }
 
-macro_rules! replace_expr {
-    ($_t:tt $repl:expr) => {
-        $repl
-    };
-}
-
 impl_query_for_tuple!();
 impl_query_for_tuple!(A);
 impl_query_for_tuple!(A B);
 impl_query_for_tuple!(A B C);
 impl_query_for_tuple!(A B C D);
 impl_query_for_tuple!(A B C D E);
+
+#[cfg(test)]
+mod tests {
+    use super::{Added, Changed, Or, Query, With, Without};
+    use crate::components::{Collision, Facing, Position, Velocity};
+    use crate::ecs::{Ecs, EntityId};
+
+    // Ids matching the query, sorted so that storage order doesn't matter
+    fn matching<Q>(ecs: &Ecs) -> Vec<EntityId>
+    where
+        Q: Query + 'static,
+    {
+        let mut ids: Vec<EntityId> = ecs.query::<(EntityId, Q)>().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn fetch_and_candidates() {
+        let mut ecs = Ecs::new();
+        for _ in 0..3 {
+            let id = ecs.add_entity();
+            ecs.add_component(id, Position::default());
+        }
+        let both = ecs.add_entity();
+        ecs.add_component(both, Position::default());
+        ecs.add_component(both, Velocity::default());
+
+        // A component that was never added can't match anything
+        assert!(<&Collision as Query>::fetch(&ecs).is_none());
+        assert!(<Option<&Collision> as Query>::fetch(&ecs).is_some());
+        assert!(matching::<&Collision>(&ecs).is_empty());
+
+        // A tuple iterates its smallest storage
+        let fetch = <(&Position, &Velocity)>::fetch(&ecs).unwrap();
+        assert_eq!(<(&Position, &Velocity)>::candidates(fetch), Some(&[both][..]));
+        // Filters without a storage of their own leave it to the rest of the tuple
+        let fetch = <(&Position, Without<Velocity>)>::fetch(&ecs).unwrap();
+        assert_eq!(<(&Position, Without<Velocity>)>::candidates(fetch).unwrap().len(), 4);
+        assert_eq!(matching::<(&Position, Without<Velocity>)>(&ecs).len(), 3);
+    }
+
+    #[test]
+    fn only_live_entities_get_components() {
+        let mut ecs = Ecs::new();
+        let live = ecs.add_entity();
+        ecs.add_component(live, Position::default());
+        let removed = ecs.add_entity();
+        ecs.remove_entity(removed);
+        ecs.add_component(removed, Position::default());
+
+        assert!(!ecs.storage::<Position>().unwrap().contains(removed));
+        assert_eq!(matching::<&Position>(&ecs), vec![live]);
+        assert!(ecs.query_one_with_id::<&Position>(removed).is_none());
+    }
+
+    #[test]
+    fn with_and_without() {
+        let mut ecs = Ecs::new();
+        let both = ecs.add_entity();
+        ecs.add_component(both, Position::default());
+        ecs.add_component(both, Velocity::default());
+        let position_only = ecs.add_entity();
+        ecs.add_component(position_only, Position::default());
+
+        assert_eq!(matching::<(With<Position>, With<Velocity>)>(&ecs), vec![both]);
+        assert_eq!(matching::<(With<Position>, Without<Velocity>)>(&ecs), vec![position_only]);
+        // A component that was never added doesn't exclude anything
+        assert_eq!(matching::<(With<Position>, Without<Collision>)>(&ecs).len(), 2);
+    }
+
+    #[test]
+    fn changed_since_system_last_ran() {
+        let mut ecs = Ecs::new();
+        let a = ecs.add_entity();
+        ecs.add_component(a, Position::default());
+        let b = ecs.add_entity();
+        ecs.add_component(b, Position::default());
+
+        // Added before the system ever ran
+        ecs.begin_system(0);
+        assert_eq!(matching::<Changed<Position>>(&ecs).len(), 2);
+        let last_run_tick = ecs.change_tick();
+
+        // Nothing changed since
+        ecs.begin_system(last_run_tick);
+        assert!(matching::<Changed<Position>>(&ecs).is_empty());
+
+        // Mutably borrowing alone isn't a change, only mutating is
+        let other_last_run_tick = ecs.change_tick();
+        ecs.begin_system(other_last_run_tick);
+        for (id, mut position) in ecs.query::<(EntityId, &mut Position)>() {
+            if id == b {
+                position.map_pos.x = 1.;
+            }
+        }
+        let other_last_run_tick = ecs.change_tick();
+
+        // Seen by another system, but not by the system that made the change
+        ecs.begin_system(last_run_tick);
+        assert_eq!(matching::<Changed<Position>>(&ecs), vec![b]);
+        ecs.begin_system(other_last_run_tick);
+        assert!(matching::<Changed<Position>>(&ecs).is_empty());
+    }
+
+    #[test]
+    fn added_on_insert_only() {
+        let mut ecs = Ecs::new();
+        let a = ecs.add_entity();
+        ecs.add_component(a, Position::default());
+        ecs.begin_system(0);
+        assert_eq!(matching::<Added<Position>>(&ecs), vec![a]);
+        let last_run_tick = ecs.change_tick();
+
+        // Mutated, replaced, and a new one inserted
+        ecs.begin_system(last_run_tick);
+        ecs.query_one_with_id::<&mut Position>(a).unwrap().map_pos.x = 1.;
+        ecs.add_component(a, Position::default());
+        let b = ecs.add_entity();
+        ecs.add_component(b, Position::default());
+
+        ecs.begin_system(last_run_tick);
+        assert_eq!(matching::<Added<Position>>(&ecs), vec![b]);
+        let mut changed = vec![a, b];
+        changed.sort();
+        assert_eq!(matching::<Changed<Position>>(&ecs), changed);
+    }
+
+    #[test]
+    fn bypassing_change_detection() {
+        let mut ecs = Ecs::new();
+        let id = ecs.add_entity();
+        ecs.add_component(id, Position::default());
+        ecs.begin_system(0);
+        let last_run_tick = ecs.change_tick();
+
+        ecs.begin_system(last_run_tick);
+        for mut position in ecs.query::<&mut Position>() {
+            position.bypass_change_detection().map_pos.x = 1.;
+        }
+
+        ecs.begin_system(last_run_tick);
+        assert!(matching::<Changed<Position>>(&ecs).is_empty());
+        assert_eq!(ecs.query_one_with_id::<&Position>(id).unwrap().map_pos.x, 1.);
+    }
+
+    #[test]
+    fn or_matches_any() {
+        let mut ecs = Ecs::new();
+        let position = ecs.add_entity();
+        ecs.add_component(position, Position::default());
+        let facing = ecs.add_entity();
+        ecs.add_component(facing, Facing::default());
+        let neither = ecs.add_entity();
+        ecs.add_component(neither, Velocity::default());
+
+        let mut expected = vec![position, facing];
+        expected.sort();
+        assert_eq!(matching::<Or<(With<Position>, With<Facing>)>>(&ecs), expected);
+
+        ecs.begin_system(0);
+        let last_run_tick = ecs.change_tick();
+        ecs.begin_system(last_run_tick);
+        ecs.query_one_with_id::<&mut Facing>(facing).unwrap().0 = Default::default();
+        let changed_tick = ecs.change_tick();
+
+        ecs.begin_system(last_run_tick);
+        assert_eq!(matching::<Or<(Changed<Position>, Changed<Facing>)>>(&ecs), vec![facing]);
+        ecs.begin_system(changed_tick);
+        assert!(matching::<Or<(Changed<Position>, Changed<Facing>)>>(&ecs).is_empty());
+    }
+}
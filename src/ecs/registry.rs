@@ -0,0 +1,56 @@
+use super::{Component, Ecs, EntityId};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+// Type-erased functions for operating on a component given only its name
+// Every serde component is registered once (in components::register_components), and everything
+// that refers to components by name (JSON loading and saving, Lua callbacks, dev ui) goes
+// through the registry
+#[derive(Clone, Copy)]
+pub struct ComponentRegistration {
+    pub name: &'static str,
+    pub add_from_value: fn(&mut Ecs, EntityId, &serde_json::Value) -> anyhow::Result<()>,
+    pub remove: fn(&mut Ecs, EntityId),
+    pub save_to_value: fn(&Ecs, EntityId) -> Option<serde_json::Value>,
+}
+
+pub struct ComponentRegistry {
+    // Vec rather than map to keep registration order for saving and displaying
+    registrations: Vec<ComponentRegistration>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self { registrations: Vec::new() }
+    }
+
+    pub fn register<C>(&mut self)
+    where
+        C: Component + Serialize + DeserializeOwned + 'static,
+    {
+        if self.get(C::name()).is_some() {
+            log::error!("Component registered more than once: {}", C::name());
+            return;
+        }
+
+        self.registrations.push(ComponentRegistration {
+            name: C::name(),
+            add_from_value: |ecs, id, value| {
+                ecs.add_component(id, serde_json::from_value::<C>(value.clone())?);
+                Ok(())
+            },
+            remove: |ecs, id| ecs.remove_component::<C>(id),
+            save_to_value: |ecs, id| {
+                ecs.query_one_with_id::<&C>(id).and_then(|c| serde_json::to_value(&*c).ok())
+            },
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentRegistration> {
+        self.registrations.iter().find(|r| r.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ComponentRegistration> {
+        self.registrations.iter()
+    }
+}
@@ -0,0 +1,125 @@
+use super::EntityId;
+use slotmap::SecondaryMap;
+use std::cell::{Cell, RefCell};
+
+pub struct ComponentCell<C> {
+    pub component: RefCell<C>,
+    // The tick at which the component was added (replacing it doesn't count)
+    pub added_tick: u32,
+    // The tick at which the component was added or last changed
+    pub changed_tick: Cell<u32>,
+}
+
+impl<C> ComponentCell<C> {
+    pub fn set_changed(&self, tick: u32) {
+        self.changed_tick.set(tick);
+    }
+}
+
+// Sparse set storage for one component type
+// The components are packed densely with the ids of their entities, so that queries can iterate
+// the smallest storage involved directly, and only do a cheap sparse lookup in the others
+pub struct SparseSet<C> {
+    sparse: SecondaryMap<EntityId, usize>,
+    ids: Vec<EntityId>,
+    cells: Vec<ComponentCell<C>>,
+}
+
+impl<C> SparseSet<C> {
+    pub fn new() -> Self {
+        Self { sparse: SecondaryMap::new(), ids: Vec::new(), cells: Vec::new() }
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&ComponentCell<C>> {
+        self.sparse.get(id).map(|i| &self.cells[*i])
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.sparse.contains_key(id)
+    }
+
+    // Returns the replaced cell if the entity already had the component
+    pub fn insert(&mut self, id: EntityId, cell: ComponentCell<C>) -> Option<ComponentCell<C>> {
+        if let Some(i) = self.sparse.get(id) {
+            return Some(std::mem::replace(&mut self.cells[*i], cell));
+        }
+
+        self.sparse.insert(id, self.ids.len());
+        self.ids.push(id);
+        self.cells.push(cell);
+        None
+    }
+
+    pub fn remove(&mut self, id: EntityId) -> Option<ComponentCell<C>> {
+        let i = self.sparse.remove(id)?;
+
+        // Swap the last component into the removed one's place
+        self.ids.swap_remove(i);
+        let cell = self.cells.swap_remove(i);
+        if let Some(moved_id) = self.ids.get(i) {
+            self.sparse[*moved_id] = i;
+        }
+
+        Some(cell)
+    }
+
+    // Ids of all entities with the component, in storage order
+    pub fn ids(&self) -> &[EntityId] {
+        &self.ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ComponentCell, SparseSet};
+    use crate::ecs::EntityId;
+    use slotmap::SlotMap;
+    use std::cell::{Cell, RefCell};
+
+    fn cell(value: i32) -> ComponentCell<i32> {
+        ComponentCell { component: RefCell::new(value), added_tick: 0, changed_tick: Cell::new(0) }
+    }
+
+    fn value(storage: &SparseSet<i32>, id: EntityId) -> Option<i32> {
+        storage.get(id).map(|cell| *cell.component.borrow())
+    }
+
+    #[test]
+    fn insert_and_replace() {
+        let mut ids = SlotMap::<EntityId, ()>::with_key();
+        let (a, b) = (ids.insert(()), ids.insert(()));
+        let mut storage = SparseSet::new();
+
+        assert!(storage.insert(a, cell(1)).is_none());
+        assert!(storage.insert(b, cell(2)).is_none());
+        let replaced = storage.insert(a, cell(3)).unwrap();
+
+        assert_eq!(*replaced.component.borrow(), 1);
+        assert_eq!(value(&storage, a), Some(3));
+        assert_eq!(value(&storage, b), Some(2));
+        assert_eq!(storage.ids(), &[a, b]);
+    }
+
+    #[test]
+    fn remove_swaps_last_into_place() {
+        let mut ids = SlotMap::<EntityId, ()>::with_key();
+        let (a, b, c) = (ids.insert(()), ids.insert(()), ids.insert(()));
+        let mut storage = SparseSet::new();
+        storage.insert(a, cell(1));
+        storage.insert(b, cell(2));
+        storage.insert(c, cell(3));
+
+        assert_eq!(*storage.remove(a).unwrap().component.borrow(), 1);
+        assert!(storage.remove(a).is_none());
+        assert!(!storage.contains(a));
+        assert_eq!(storage.ids(), &[c, b]);
+        // The moved component is still found through its id
+        assert_eq!(value(&storage, c), Some(3));
+        assert_eq!(value(&storage, b), Some(2));
+
+        // Removing the last one has nothing to swap
+        storage.remove(b);
+        assert_eq!(storage.ids(), &[c]);
+        assert_eq!(value(&storage, c), Some(3));
+    }
+}
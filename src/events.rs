@@ -0,0 +1,66 @@
+use crate::ecs::{Ecs, EntityId, Event};
+
+// Every event must be added here to be sendable
+// (Lua subscriptions also need script::events::lua_events)
+pub fn add_events(ecs: &mut Ecs) {
+    ecs.add_event::<AnimationFinished>();
+    ecs.add_event::<WalkFinished>();
+    ecs.add_event::<EnteredArea>();
+    ecs.add_event::<ExitedArea>();
+    ecs.add_event::<Interacted>();
+    ecs.add_event::<StoryVarChanged>();
+    ecs.add_event::<MapChanged>();
+}
+
+// A non-repeating animation played to its end
+#[derive(Debug, Clone)]
+pub struct AnimationFinished {
+    pub entity: EntityId,
+}
+impl Event for AnimationFinished {}
+
+// A walk with a destination reached it
+#[derive(Debug, Clone)]
+pub struct WalkFinished {
+    pub entity: EntityId,
+}
+impl Event for WalkFinished {}
+
+// The player entered or exited the hitbox of an area trigger
+#[derive(Debug, Clone)]
+pub struct EnteredArea {
+    pub entity: EntityId,
+    pub area: EntityId,
+}
+impl Event for EnteredArea {}
+
+#[derive(Debug, Clone)]
+pub struct ExitedArea {
+    pub entity: EntityId,
+    pub area: EntityId,
+}
+impl Event for ExitedArea {}
+
+// The player interacted with an entity with an interaction trigger
+#[derive(Debug, Clone)]
+pub struct Interacted {
+    pub entity: EntityId,
+}
+impl Event for Interacted {}
+
+#[derive(Debug, Clone)]
+pub struct StoryVarChanged {
+    pub key: String,
+    // None when the var didn't exist yet
+    pub old_value: Option<i32>,
+    pub new_value: i32,
+}
+impl Event for StoryVarChanged {}
+
+// The camera moved to another map, or a teleport moved the player to another map
+#[derive(Debug, Clone)]
+pub struct MapChanged {
+    pub old_map: Option<String>,
+    pub new_map: String,
+}
+impl Event for MapChanged {}
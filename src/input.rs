@@ -1,9 +1,13 @@
 use crate::components::{AnimationComp, Facing, InteractionTrigger, NamedAnims, Position, Walking};
 use crate::data::PLAYER_ENTITY_NAME;
+use crate::ecs::EntityId;
+use crate::events::Interacted;
 use crate::math::Vec2;
-use crate::misc::{Aabb, Direction};
+use crate::misc::{Aabb, Direction, PlayerMovementLocked, Running};
+use crate::save::{self, QUICKSAVE_PATH};
 use crate::script::ScriptManager;
-use crate::{DevUi, GameData, MessageWindow};
+use crate::spatial::SpatialIndex;
+use crate::{DevUi, GameData, UiData};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use tap::TapFallible;
@@ -11,24 +15,41 @@ use tap::TapFallible;
 pub fn process_input(
     game_data: &mut GameData,
     event_pump: &mut sdl2::EventPump,
-    running: &mut bool,
-    message_window: &mut Option<MessageWindow>,
-    player_movement_locked: bool,
     dev_ui: &mut DevUi,
     script_manager: &mut ScriptManager,
 ) {
-    let GameData { ecs, .. } = game_data;
-
     for event in event_pump.poll_iter() {
         // Update egui state with new input
         dev_ui.state.sdl2_input_to_egui(dev_ui.window, &event);
 
+        // Quicksave and quickload
+        match event {
+            Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                match save::save_game(game_data, QUICKSAVE_PATH) {
+                    Ok(()) => log::info!("Saved game to `{QUICKSAVE_PATH}`"),
+                    Err(e) => log::error!("Couldn't save game (err: {e})"),
+                }
+                continue;
+            }
+            Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                match save::load_game(game_data, script_manager, QUICKSAVE_PATH) {
+                    Ok(()) => log::info!("Loaded game from `{QUICKSAVE_PATH}`"),
+                    Err(e) => log::error!("Couldn't load game (err: {e})"),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let GameData { ecs, .. } = &*game_data;
+        let mut ui_data = ecs.resource_mut::<UiData>();
+        let player_movement_locked = ecs.resource::<PlayerMovementLocked>().0;
+
         match event {
             // Arbitrary testing
             Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                let (mut ac, na) = ecs
-                    .query_one_with_name::<(&mut AnimationComp, &NamedAnims)>(PLAYER_ENTITY_NAME)
-                    .unwrap();
+                let (mut ac, na) =
+                    ecs.query_one::<(&mut AnimationComp, &NamedAnims)>(PLAYER_ENTITY_NAME).unwrap();
                 ac.clip = na.get("spin").unwrap().clone();
                 ac.forced = true;
                 ac.start(false);
@@ -36,7 +57,7 @@ pub fn process_input(
 
             // Close program
             Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                *running = false;
+                ecs.resource_mut::<Running>().0 = false;
             }
 
             // Toggle dev ui
@@ -51,15 +72,13 @@ pub fn process_input(
                     || keycode == Keycode::Left
                     || keycode == Keycode::Right =>
             {
-                let message_window: &Option<MessageWindow> = &*message_window;
-                let (mut facing, mut walking_component) = ecs
-                    .query_one_with_name::<(&mut Facing, &mut Walking)>(PLAYER_ENTITY_NAME)
-                    .unwrap();
+                let (mut facing, mut walking_component) =
+                    ecs.query_one::<(&mut Facing, &mut Walking)>(PLAYER_ENTITY_NAME).unwrap();
 
                 // Some conditions (such as a message window open, or movement being forced)
                 // lock player movement. Scripts can also lock/unlock it
                 // as necessary.
-                if message_window.is_none()
+                if ui_data.message_window.is_none()
                     && walking_component.destination.is_none()
                     && !player_movement_locked
                 {
@@ -78,11 +97,7 @@ pub fn process_input(
             // End player movement if key matching player direction is released
             Event::KeyUp { keycode: Some(keycode), .. }
                 if keycode
-                    == match ecs
-                        .query_one_with_name::<&Walking>(PLAYER_ENTITY_NAME)
-                        .unwrap()
-                        .direction
-                    {
+                    == match ecs.query_one::<&Walking>(PLAYER_ENTITY_NAME).unwrap().direction {
                         Direction::Up => Keycode::Up,
                         Direction::Down => Keycode::Down,
                         Direction::Left => Keycode::Left,
@@ -90,7 +105,7 @@ pub fn process_input(
                     } =>
             {
                 let mut walking_component =
-                    ecs.query_one_with_name::<&mut Walking>(PLAYER_ENTITY_NAME).unwrap();
+                    ecs.query_one::<&mut Walking>(PLAYER_ENTITY_NAME).unwrap();
                 // Don't end movement if it's being forced
                 // (I need to rework the way that input vs forced movement work)
                 if walking_component.destination.is_none() {
@@ -101,8 +116,8 @@ pub fn process_input(
             // Interact with entity to start script OR advance message
             Event::KeyDown { keycode: Some(Keycode::Return | Keycode::Space), .. } => {
                 // Delegate to UI system then to world/entity system?
-                if message_window.is_some() {
-                    *message_window = None;
+                if ui_data.message_window.is_some() {
+                    ui_data.message_window = None;
                 } else {
                     // Block interactions if movement is locked (it's really more like all player
                     // entity control is locked)
@@ -113,9 +128,8 @@ pub fn process_input(
                     // for the presence of an entity with an
                     // interaction script. This fails in some cases,
                     // but it works okay for now.
-                    let (player_position, player_facing) = ecs
-                        .query_one_with_name::<(&Position, &Facing)>(PLAYER_ENTITY_NAME)
-                        .unwrap();
+                    let (player_position, player_facing) =
+                        ecs.query_one::<(&Position, &Facing)>(PLAYER_ENTITY_NAME).unwrap();
                     let target = player_position.map_pos
                         + match player_facing.0 {
                             Direction::Up => Vec2::new(0.0, -0.5),
@@ -126,12 +140,18 @@ pub fn process_input(
 
                     // Start interaction scripts for entity with interaction hitbox containing
                     // target point
-                    for (_, interaction) in ecs.query::<(&Position, &InteractionTrigger)>().filter(
-                        |(position, interaction)| {
-                            position.map == player_position.map
-                                && Aabb::new(position.map_pos, interaction.hitbox).contains(&target)
-                        },
-                    ) {
+                    let candidates =
+                        ecs.resource::<SpatialIndex>().query_point(&player_position.map, target);
+                    for (id, _, interaction) in candidates
+                        .into_iter()
+                        .filter_map(|id| {
+                            ecs.query_one_with_id::<(EntityId, &Position, &InteractionTrigger)>(id)
+                        })
+                        .filter(|(_, position, interaction)| {
+                            Aabb::new(position.map_pos, interaction.hitbox).contains(&target)
+                        })
+                    {
+                        ecs.send_event(Interacted { entity: id });
                         if let Ok(source) = interaction
                             .script_source
                             .get_source()
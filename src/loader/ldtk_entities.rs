@@ -1,16 +1,18 @@
-use super::ldtk_project;
+use super::{ldtk_project, prefabs};
 use crate::components::{
-    AnimationClip, AnimationComp, AreaTrigger, CharacterAnims, Collision, DualStateAnimationState,
-    DualStateAnims, Facing, InteractionTrigger, Name, Position, ScriptSource, Sprite, SpriteComp,
-    Walking,
+    AnimationClip, AnimationComp, AreaTrigger, CharacterAnims, Collision, Door, DoorState,
+    DualStateAnimationState, DualStateAnims, Facing, InteractionTrigger, Name, Parent, Position,
+    ScriptSource, Sprite, SpriteComp, Tags, Teleport, TeleportDestination, Walking,
 };
-use crate::ecs::{Ecs, EntityId};
-use crate::math::{Rect, Vec2};
+use crate::ecs::{Component, Ecs, EntityId, EntityIdentifier, With};
+use crate::math::{MapUnits, Rect, Vec2};
+use crate::misc::Direction;
 use crate::world::WorldPos;
 use anyhow::Context;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
 use std::format as f;
 
 // Old script triggers because LDtk entities still reference them
@@ -23,59 +25,109 @@ pub enum Trigger {
     HardCollision,
 }
 
+// Adds the components of a specific kind of entity, after the common ones
+type LoadEntityFn =
+    fn(&mut Ecs, EntityId, &ldtk_project::EntityInstance, f64) -> anyhow::Result<()>;
+
 pub fn load_entities_from_ldtk(ecs: &mut Ecs, project: &ldtk_project::Project) {
-    for ldtk_world in &project.worlds {
-        for level in &ldtk_world.levels {
-            for entity in level
+    // Pixels per map unit
+    let cell_size = project.default_grid_size as f64;
+
+    // Ldtk iids of loaded entities, for resolving entity references
+    let mut iids: HashMap<&str, EntityId> = HashMap::new();
+
+    for (ldtk_world, level, entity) in entity_instances(project) {
+        let load: Option<LoadEntityFn> = match entity.identifier.as_str() {
+            "generic" => None,
+            // (The Teleport component is added once every entity exists)
+            "teleport" => None,
+            "simple_script" => Some(load_simple_script_entity),
+            "simple_anim" => Some(load_simple_animation_entity),
+            "dual_state_anim" => Some(load_dual_state_animation_entity),
+            "character" => Some(load_character_entity),
+            "door" => Some(load_door_entity),
+            _ => continue,
+        };
+
+        let id = ecs.add_entity();
+        let r: anyhow::Result<()> = try {
+            add_common_components(ecs, id, entity, ldtk_world, level, cell_size)?;
+            if let Some(load) = load {
+                load(ecs, id, entity, cell_size)?;
+            }
+        };
+        match r {
+            Ok(()) => {
+                iids.insert(&entity.iid, id);
+            }
+            Err(e) => log::error!("Invalid ldtk entity `{}` (err: {e})", entity.iid),
+        }
+    }
+
+    // Parents and teleports are added once every entity exists, since the entities they refer to
+    // might come after them
+    for (_, _, entity) in entity_instances(project) {
+        let Some(id) = iids.get(entity.iid.as_str()).copied() else {
+            continue;
+        };
+        let r: anyhow::Result<()> = try {
+            add_parent_component(ecs, id, entity, &iids)?;
+            if entity.identifier == "teleport" {
+                add_teleport_component(ecs, id, entity, &iids, cell_size)?;
+            }
+        };
+        r.unwrap_or_else(|e| log::error!("Invalid ldtk entity `{}` (err: {e})", entity.iid));
+    }
+}
+
+fn entity_instances(
+    project: &ldtk_project::Project,
+) -> impl Iterator<Item = (&ldtk_project::World, &ldtk_project::Level, &ldtk_project::EntityInstance)>
+{
+    project.worlds.iter().flat_map(|ldtk_world| {
+        ldtk_world.levels.iter().flat_map(move |level| {
+            level
                 .layer_instances
                 .as_ref()
-                .expect("levels not saved separately")
+                .expect("external levels are loaded with the project")
                 .iter()
                 .flat_map(|layer| &layer.entity_instances)
-            {
-                let r: anyhow::Result<()> = try {
-                    match entity.identifier.as_str() {
-                        "generic" => {
-                            load_generic_entity(ecs, entity, ldtk_world, level)?;
-                        }
-                        "simple_script" => {
-                            load_simple_script_entity(ecs, entity, ldtk_world, level)?;
-                        }
-                        "simple_anim" => {
-                            load_simple_animation_entity(ecs, entity, ldtk_world, level)?;
-                        }
-                        "dual_state_anim" => {
-                            load_dual_state_animation_entity(ecs, entity, ldtk_world, level)?;
-                        }
-                        "character" => {
-                            load_character_entity(ecs, entity, ldtk_world, level)?;
-                        }
-                        _ => {}
-                    }
-                };
-                r.unwrap_or_else(|e| log::error!("Invalid ldtk entity `{}` (err: {e})", entity.iid))
-            }
-        }
-    }
+                .map(move |entity| (ldtk_world, level, entity))
+        })
+    })
 }
 
 // --------------------------------------------------------------
 // Ldtk entities
 // --------------------------------------------------------------
 
-fn load_generic_entity(
+// Prefab, position, name, tags and JSON components, which any kind of entity can have
+// (Before the components of the specific kind, which override these)
+fn add_common_components(
     ecs: &mut Ecs,
+    id: EntityId,
     entity: &ldtk_project::EntityInstance,
     ldtk_world: &ldtk_project::World,
     level: &ldtk_project::Level,
+    cell_size: f64,
 ) -> anyhow::Result<()> {
-    let id = ecs.add_entity();
+    // Prefab (first, so that everything else overrides it)
+    if let Some(prefab) = read_field::<String>("prefab", entity)? {
+        prefabs::add_prefab_components(ecs, id, &prefab, &Map::new())?;
+    }
 
-    add_position_component(ecs, id, entity, ldtk_world, level);
+    add_position_component(ecs, id, entity, ldtk_world, level, cell_size);
 
     // Name
-    if let Some(name) = read_field("name", entity)? {
-        ecs.add_component(id, Name(name));
+    if let Some(name) = read_field::<String>("name", entity)? {
+        ecs.add_component(id, Name::new(name));
+    }
+
+    // Tags
+    if let Some(tags) = read_field::<Vec<String>>("tags", entity)?
+        && !tags.is_empty()
+    {
+        ecs.add_component(id, Tags(tags.into_iter().collect()));
     }
 
     // JSON components
@@ -90,54 +142,26 @@ fn load_generic_entity(
 
 fn load_simple_script_entity(
     ecs: &mut Ecs,
+    id: EntityId,
     entity: &ldtk_project::EntityInstance,
-    ldtk_world: &ldtk_project::World,
-    level: &ldtk_project::Level,
+    cell_size: f64,
 ) -> anyhow::Result<()> {
-    let id = ecs.add_entity();
-
-    add_position_component(ecs, id, entity, ldtk_world, level);
-
-    // Name
-    if let Some(name) = read_field("name", entity)? {
-        ecs.add_component(id, Name(name));
-    }
-
-    // JSON components
-    if let Some(Value::Object(components_map)) = read_json_field("json_components", entity)? {
-        for (key, val) in components_map {
-            ecs.add_component_with_name(id, &key, &val)?;
-        }
-    }
-
     // Script
 
-    let source = if let Some(source_name) = read_field::<String>("external_source", entity)? {
-        let (file_name, subscript_label) = source_name
-            .split_once("::")
-            .context(f!("invalid script source name `{source_name}`"))?;
-        ScriptSource::File {
-            filepath: f!("data/{file_name}.lua"),
-            name_in_file: Some(subscript_label.to_string()),
-        }
-    } else {
-        ScriptSource::String(read_field("source", entity)?.unwrap_or_default())
-    };
+    let source = read_script_source(entity)?;
 
     let trigger = read_field("trigger", entity)?;
     match trigger {
         Some(Trigger::Interaction) => ecs.add_component(
             id,
-            InteractionTrigger {
-                script_source: source,
-                hitbox: Vec2::new(entity.width as f64 / 16., entity.height as f64 / 16.),
-            },
+            InteractionTrigger { script_source: source, hitbox: entity_hitbox(entity, cell_size) },
         ),
         Some(Trigger::SoftCollision) => ecs.add_component(
             id,
             AreaTrigger {
                 script_source: source,
-                hitbox: Vec2::new(entity.width as f64 / 16., entity.height as f64 / 16.),
+                hitbox: entity_hitbox(entity, cell_size),
+                player_inside: false,
             },
         ),
         _ => {}
@@ -148,26 +172,10 @@ fn load_simple_script_entity(
 
 fn load_simple_animation_entity(
     ecs: &mut Ecs,
+    id: EntityId,
     entity: &ldtk_project::EntityInstance,
-    ldtk_world: &ldtk_project::World,
-    level: &ldtk_project::Level,
+    _cell_size: f64,
 ) -> anyhow::Result<()> {
-    let id = ecs.add_entity();
-
-    add_position_component(ecs, id, entity, ldtk_world, level);
-
-    // Name
-    if let Some(name) = read_field("name", entity)? {
-        ecs.add_component(id, Name(name));
-    }
-
-    // JSON components
-    if let Some(Value::Object(components_map)) = read_json_field("json_components", entity)? {
-        for (key, val) in components_map {
-            ecs.add_component_with_name(id, &key, &val)?;
-        }
-    }
-
     // Sprite
     if let Some(visible) = read_field("visible", entity)? {
         ecs.add_component(id, SpriteComp { visible, ..Default::default() });
@@ -206,26 +214,10 @@ fn load_simple_animation_entity(
 
 fn load_dual_state_animation_entity(
     ecs: &mut Ecs,
+    id: EntityId,
     entity: &ldtk_project::EntityInstance,
-    ldtk_world: &ldtk_project::World,
-    level: &ldtk_project::Level,
+    _cell_size: f64,
 ) -> anyhow::Result<()> {
-    let id = ecs.add_entity();
-
-    add_position_component(ecs, id, entity, ldtk_world, level);
-
-    // Name
-    if let Some(name) = read_field("name", entity)? {
-        ecs.add_component(id, Name(name));
-    }
-
-    // JSON components
-    if let Some(Value::Object(components_map)) = read_json_field("json_components", entity)? {
-        for (key, val) in components_map {
-            ecs.add_component_with_name(id, &key, &val)?;
-        }
-    }
-
     // Sprite
     let visible = read_field_required("visible", entity)?;
     ecs.add_component(id, SpriteComp { visible, ..Default::default() });
@@ -238,20 +230,8 @@ fn load_dual_state_animation_entity(
     let second_to_first: Vec<u32> = read_json_field_required("second_to_first", entity)?;
     let seconds_per_frame = read_field_required("seconds_per_frame", entity)?;
 
-    let w = entity.width;
-    let h = entity.height;
-
-    let clip_from_frame_indexes = |cols: &[u32]| AnimationClip {
-        frames: cols
-            .iter()
-            .map(|col| Sprite {
-                spritesheet: spritesheet.clone(),
-                rect: Rect::new(col * w as u32, 0, w as u32, h as u32),
-                anchor: Vec2::new(w as i32 / 2, h as i32 / 2),
-            })
-            .collect(),
-        seconds_per_frame,
-    };
+    let clip_from_frame_indexes =
+        |cols: &[u32]| clip_from_frame_indexes(&spritesheet, cols, entity, seconds_per_frame);
 
     ecs.add_component(
         id,
@@ -273,92 +253,277 @@ fn load_dual_state_animation_entity(
 
 fn load_character_entity(
     ecs: &mut Ecs,
+    id: EntityId,
     entity: &ldtk_project::EntityInstance,
-    ldtk_world: &ldtk_project::World,
-    level: &ldtk_project::Level,
+    cell_size: f64,
 ) -> anyhow::Result<()> {
-    let id = ecs.add_entity();
+    // Anything the prefab or JSON components already added is kept
 
-    add_position_component(ecs, id, entity, ldtk_world, level);
+    // Collision
+    if !has_component::<Collision>(ecs, id) {
+        ecs.add_component(
+            id,
+            Collision { hitbox: Vec2::new(14. / cell_size, 6. / cell_size), solid: true },
+        );
+    }
 
-    // Name
-    if let Some(name) = read_field("name", entity)? {
-        ecs.add_component(id, Name(name));
+    // Animation
+    if !has_component::<CharacterAnims>(ecs, id) {
+        let spritesheet = read_field_required::<String>("spritesheet", entity)?;
+
+        let clip_from_frames = |frames: Vec<(u32, u32)>| AnimationClip {
+            frames: frames
+                .into_iter()
+                .map(|(col, row)| Sprite {
+                    spritesheet: spritesheet.clone(),
+                    rect: Rect::new(col * 16, row * 32, 16, 32),
+                    anchor: Vec2::new(8, 29),
+                })
+                .collect(),
+            seconds_per_frame: 0.2,
+        };
+
+        ecs.add_component(
+            id,
+            CharacterAnims {
+                up: clip_from_frames(vec![(6, 2), (1, 0), (9, 2), (1, 0)]),
+                down: clip_from_frames(vec![(18, 2), (3, 0), (21, 2), (3, 0)]),
+                left: clip_from_frames(vec![(12, 2), (2, 0), (15, 2), (2, 0)]),
+                right: clip_from_frames(vec![(0, 2), (0, 0), (3, 2), (0, 0)]),
+            },
+        );
+    }
+    if !has_component::<AnimationComp>(ecs, id) {
+        ecs.add_component(id, AnimationComp::default());
     }
 
-    // JSON components
-    if let Some(Value::Object(components_map)) = read_json_field("json_components", entity)? {
-        for (key, val) in components_map {
-            ecs.add_component_with_name(id, &key, &val)?;
-        }
+    // Misc
+    if !has_component::<SpriteComp>(ecs, id) {
+        ecs.add_component(id, SpriteComp::default());
+    }
+    if !has_component::<Facing>(ecs, id) {
+        ecs.add_component(id, Facing::default());
+    }
+    if !has_component::<Walking>(ecs, id) {
+        ecs.add_component(id, Walking::default());
     }
 
-    // Collision
-    ecs.add_component(id, Collision { hitbox: Vec2::new(14. / 16., 6. / 16.), solid: true });
+    Ok(())
+}
 
-    // Animation
+// Interacting with the door runs its script (if any) and opens or closes it
+fn load_door_entity(
+    ecs: &mut Ecs,
+    id: EntityId,
+    entity: &ldtk_project::EntityInstance,
+    cell_size: f64,
+) -> anyhow::Result<()> {
+    // Door
     let spritesheet = read_field_required::<String>("spritesheet", entity)?;
-
-    let clip_from_frames = |frames: Vec<(u32, u32)>| AnimationClip {
-        frames: frames
-            .into_iter()
-            .map(|(col, row)| Sprite {
-                spritesheet: spritesheet.clone(),
-                rect: Rect::new(col * 16, row * 32, 16, 32),
-                anchor: Vec2::new(8, 29),
-            })
-            .collect(),
-        seconds_per_frame: 0.2,
+    let seconds_per_frame = read_field_required("seconds_per_frame", entity)?;
+    let clip = |field: &str| -> anyhow::Result<Option<AnimationClip>> {
+        Ok(read_json_field::<Vec<u32>>(field, entity)?
+            .map(|cols| clip_from_frame_indexes(&spritesheet, &cols, entity, seconds_per_frame)))
     };
 
-    ecs.add_component(id, AnimationComp::default());
+    let state = read_field("state", entity)?.unwrap_or(DoorState::Closed);
     ecs.add_component(
         id,
-        CharacterAnims {
-            up: clip_from_frames(vec![(6, 2), (1, 0), (9, 2), (1, 0)]),
-            down: clip_from_frames(vec![(18, 2), (3, 0), (21, 2), (3, 0)]),
-            left: clip_from_frames(vec![(12, 2), (2, 0), (15, 2), (2, 0)]),
-            right: clip_from_frames(vec![(0, 2), (0, 0), (3, 2), (0, 0)]),
+        Door {
+            state,
+            open: clip("open")?.context("missing required field `open`")?,
+            closed: clip("closed")?.context("missing required field `closed`")?,
+            locked: clip("locked")?,
+            opening: clip("opening")?,
+            closing: clip("closing")?,
+            key_story_var: read_field("key_story_var", entity)?,
+            animated_state: None,
         },
     );
 
-    // Misc
+    let hitbox = entity_hitbox(entity, cell_size);
+    ecs.add_component(id, Collision { hitbox, solid: state != DoorState::Open });
+    ecs.add_component(
+        id,
+        InteractionTrigger { script_source: read_script_source(entity)?, hitbox },
+    );
     ecs.add_component(id, SpriteComp::default());
-    ecs.add_component(id, Facing::default());
-    ecs.add_component(id, Walking::default());
+    ecs.add_component(id, AnimationComp::default());
 
     Ok(())
 }
 
+// A child's offset is wherever it was placed relative to its parent in the editor
+fn add_parent_component(
+    ecs: &mut Ecs,
+    id: EntityId,
+    entity: &ldtk_project::EntityInstance,
+    iids: &HashMap<&str, EntityId>,
+) -> anyhow::Result<()> {
+    let Some(reference) =
+        read_field::<ldtk_project::ReferenceToAnEntityInstance>("parent", entity)?
+    else {
+        return Ok(());
+    };
+    let parent_id = iids
+        .get(reference.entity_iid.as_str())
+        .copied()
+        .context(f!("parent `{}` isn't a loaded entity", reference.entity_iid))?;
+
+    let offset = match (
+        ecs.query_one_with_id::<&Position>(id),
+        ecs.query_one_with_id::<&Position>(parent_id),
+    ) {
+        (Some(position), Some(parent_position)) => position.map_pos - parent_position.map_pos,
+        _ => Vec2::default(),
+    };
+
+    ecs.add_component(id, Parent { entity: entity_identifier(ecs, parent_id), offset });
+
+    Ok(())
+}
+
+// The destination is either another entity (such as a marker entity) or a map and position
+fn add_teleport_component(
+    ecs: &mut Ecs,
+    id: EntityId,
+    entity: &ldtk_project::EntityInstance,
+    iids: &HashMap<&str, EntityId>,
+    cell_size: f64,
+) -> anyhow::Result<()> {
+    let destination = if let Some(reference) =
+        read_field::<ldtk_project::ReferenceToAnEntityInstance>("destination", entity)?
+    {
+        let destination_id = iids
+            .get(reference.entity_iid.as_str())
+            .copied()
+            .context(f!("destination `{}` isn't a loaded entity", reference.entity_iid))?;
+        TeleportDestination::Entity(entity_identifier(ecs, destination_id))
+    } else {
+        TeleportDestination::WorldPos(WorldPos::new(
+            &read_field_required::<String>("destination_map", entity)?,
+            read_field_required("destination_x", entity)?,
+            read_field_required("destination_y", entity)?,
+        ))
+    };
+
+    ecs.add_component(
+        id,
+        Teleport {
+            destination,
+            hitbox: entity_hitbox(entity, cell_size),
+            facing: read_field::<Direction>("facing", entity)?,
+            fade: read_field("fade", entity)?,
+            condition: read_field("condition", entity)?,
+            player_inside: false,
+        },
+    );
+
+    Ok(())
+}
+
+fn has_component<C>(ecs: &Ecs, id: EntityId) -> bool
+where
+    C: Component + 'static,
+{
+    ecs.query_one_with_id::<With<C>>(id).is_some()
+}
+
+// Refer to entities by name if they have one, to keep saves readable
+fn entity_identifier(ecs: &Ecs, id: EntityId) -> EntityIdentifier {
+    match ecs.query_one_with_id::<&Name>(id) {
+        Some(name) => EntityIdentifier::Name(name.to_string()),
+        None => EntityIdentifier::Id(id),
+    }
+}
+
+// From either a script in a file (`file::script_name`) or the source itself
+fn read_script_source(entity: &ldtk_project::EntityInstance) -> anyhow::Result<ScriptSource> {
+    if let Some(source_name) = read_field::<String>("external_source", entity)? {
+        external_script_source(&source_name)
+    } else {
+        Ok(ScriptSource::String(read_field("source", entity)?.unwrap_or_default()))
+    }
+}
+
+// A script in a file, from a name like `file::script_name`
+pub fn external_script_source(source_name: &str) -> anyhow::Result<ScriptSource> {
+    let (file_name, subscript_label) =
+        source_name.split_once("::").context(f!("invalid script source name `{source_name}`"))?;
+    Ok(ScriptSource::File {
+        filepath: f!("data/{file_name}.lua"),
+        name_in_file: Some(subscript_label.to_string()),
+    })
+}
+
+// Frames are columns of a spritesheet with one row of frames the size of the entity
+fn clip_from_frame_indexes(
+    spritesheet: &str,
+    cols: &[u32],
+    entity: &ldtk_project::EntityInstance,
+    seconds_per_frame: f64,
+) -> AnimationClip {
+    let w = entity.width as u32;
+    let h = entity.height as u32;
+    AnimationClip {
+        frames: cols
+            .iter()
+            .map(|col| Sprite {
+                spritesheet: spritesheet.to_string(),
+                rect: Rect::new(col * w, 0, w, h),
+                anchor: Vec2::new(w as i32 / 2, h as i32 / 2),
+            })
+            .collect(),
+        seconds_per_frame,
+    }
+}
+
 fn add_position_component(
     ecs: &mut Ecs,
     id: EntityId,
     entity: &ldtk_project::EntityInstance,
     ldtk_world: &ldtk_project::World,
     level: &ldtk_project::Level,
+    cell_size: f64,
 ) {
     let position = if ldtk_world.levels.iter().any(|l| l.identifier == "_world_map") {
         Position(WorldPos::new(
             &ldtk_world.identifier,
-            (entity.px[0] + level.world_x) as f64 / 16.,
-            (entity.px[1] + level.world_y) as f64 / 16.,
+            (entity.px[0] + level.world_x) as f64 / cell_size,
+            (entity.px[1] + level.world_y) as f64 / cell_size,
         ))
     } else {
         Position(WorldPos::new(
             &level.identifier,
-            entity.px[0] as f64 / 16.,
-            entity.px[1] as f64 / 16.,
+            entity.px[0] as f64 / cell_size,
+            entity.px[1] as f64 / cell_size,
         ))
     };
     ecs.add_component(id, position);
 }
 
+// The size of the entity in LDtk, in map units
+fn entity_hitbox(entity: &ldtk_project::EntityInstance, cell_size: f64) -> Vec2<f64, MapUnits> {
+    Vec2::new(entity.width as f64 / cell_size, entity.height as f64 / cell_size)
+}
+
 fn read_field<F>(field: &str, entity: &ldtk_project::EntityInstance) -> anyhow::Result<Option<F>>
+where
+    F: DeserializeOwned,
+{
+    read_field_instance(field, &entity.field_instances)
+}
+
+// Also for the fields of levels
+pub fn read_field_instance<F>(
+    field: &str,
+    field_instances: &[ldtk_project::FieldInstance],
+) -> anyhow::Result<Option<F>>
 where
     F: DeserializeOwned,
 {
     if let Some(v) =
-        entity.field_instances.iter().find(|f| f.identifier == field).and_then(|f| f.value.clone())
+        field_instances.iter().find(|f| f.identifier == field).and_then(|f| f.value.clone())
     {
         Ok(Some(serde_json::from_value::<F>(v)?))
     } else {
@@ -23,6 +23,9 @@ pub struct Project {
     /// Project background color
     pub bg_color: String,
 
+    /// Default grid size for new layers
+    pub default_grid_size: i64,
+
     /// A structure containing all the definitions of this project
     pub defs: Definitions,
 
@@ -280,6 +283,9 @@ pub struct LayerDefinition {
     /// since version 1.0.0, the old `autoTilesetDefUid` was removed and merged into this value.
     pub tileset_def_uid: Option<i64>,
 
+    /// User defined tags to filter layers in the editor
+    pub ui_filter_tags: Vec<String>,
+
     /// Unique Int identifier
     pub uid: i64,
 }
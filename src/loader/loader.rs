@@ -1,8 +1,10 @@
-use crate::ecs::{Ecs, EntityId};
+use super::{ldtk_project, prefabs};
+use crate::ecs::Ecs;
 use crate::misc::StoryVars;
 use anyhow::Context;
 use sdl2::mixer::{Chunk, Music};
 use std::collections::HashMap;
+use std::format as f;
 use std::path::Path;
 use tap::TapFallible;
 
@@ -16,7 +18,8 @@ where
     };
 
     let r: anyhow::Result<()> = try {
-        let entities_value: serde_json::Value = serde_json::from_str(&json)?;
+        let entities_value: serde_json::Value =
+            serde_json::from_str(&json).map_err(anyhow::Error::from)?;
         let entities_array = entities_value.as_array().context("not an array")?;
 
         for components_value in entities_array {
@@ -26,20 +29,31 @@ where
             // Try to get id from the json
             // If none, try to get id from preexisiting entity by name
             // If none, generate new entity
-            // (Getting id from the json is currently useless since we don't have game state
-            // saving and loading yet)
+            // (Ids are only stable across runs when restored from a save, so an id in the json
+            // is only used if it refers to an entity that currently exists)
             let id = components_map
                 .get("EntityId")
                 .and_then(|v| serde_json::from_value(v.clone()).ok())
+                .filter(|id| ecs.entity_ids.contains_key(*id))
                 .or_else(|| {
                     components_map
                         .get("Name")
                         .and_then(|v| serde_json::from_value(v.clone()).ok())
-                        .and_then(|n: String| ecs.query_one_with_name::<EntityId>(&n))
+                        .and_then(|n: String| ecs.find_entity(n))
                 })
                 .unwrap_or_else(|| ecs.add_entity());
 
-            for (key, val) in components_map.iter().filter(|(k, _)| *k != "EntityId") {
+            // An entity with a prefab is the prefab's components, overridden by its own
+            let components_map = match components_map.get("prefab") {
+                Some(prefab) => ecs
+                    .resource::<prefabs::Prefabs>()
+                    .components(prefab.as_str().context("prefab not a string")?, components_map)?,
+                None => components_map.clone(),
+            };
+
+            for (key, val) in
+                components_map.iter().filter(|(k, _)| *k != "EntityId" && *k != "prefab")
+            {
                 ecs.add_component_with_name(id, key, val).unwrap_or_else(|e| {
                     log::error!(
                         "Couldn't load component in file `{}` (err: {e})",
@@ -64,7 +78,8 @@ where
     };
 
     let r: anyhow::Result<()> = try {
-        for (key, val) in serde_json::from_str::<serde_json::Value>(&json)?
+        for (key, val) in serde_json::from_str::<serde_json::Value>(&json)
+            .map_err(anyhow::Error::from)?
             .as_object()
             .context("not an object")?
         {
@@ -76,6 +91,45 @@ where
     });
 }
 
+// With the layers of levels saved separately (.ldtkl files) read into their levels, so that
+// every level has its layer instances
+pub fn load_ldtk_project<P>(path: P) -> anyhow::Result<ldtk_project::Project>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let json = std::fs::read_to_string(path)
+        .context(f!("couldn't read file `{}`", path.to_string_lossy()))?;
+    let mut project: ldtk_project::Project = serde_json::from_str(&json)
+        .context(f!("invalid LDtk project `{}`", path.to_string_lossy()))?;
+
+    // External level paths are relative to the project file
+    let project_dir = path.parent().unwrap_or(Path::new(""));
+    for level in project.worlds.iter_mut().flat_map(|w| &mut w.levels) {
+        if level.layer_instances.is_some() {
+            continue;
+        }
+
+        let rel_path = level
+            .external_rel_path
+            .as_ref()
+            .context(f!("level `{}` has neither layers nor an external file", level.identifier))?;
+        let level_path = project_dir.join(rel_path);
+        let level_json = std::fs::read_to_string(&level_path)
+            .context(f!("couldn't read file `{}`", level_path.to_string_lossy()))?;
+        let external_level: ldtk_project::Level = serde_json::from_str(&level_json)
+            .context(f!("invalid LDtk level `{}`", level_path.to_string_lossy()))?;
+
+        level.layer_instances = Some(
+            external_level
+                .layer_instances
+                .context(f!("external level `{}` has no layers", level.identifier))?,
+        );
+    }
+
+    Ok(project)
+}
+
 pub fn load_sound_effects() -> HashMap<String, Chunk> {
     std::fs::read_dir("assets/sfx/")
         .tap_err(|_| log::error!("Couldn't open assets/sfx/"))
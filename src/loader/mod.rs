@@ -1,5 +1,6 @@
 pub mod ldtk_entities;
 pub mod ldtk_project;
+pub mod prefabs;
 
 mod loader;
 pub use loader::*;
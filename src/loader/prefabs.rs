@@ -0,0 +1,120 @@
+use crate::ecs::{Ecs, EntityId};
+use anyhow::{Context, anyhow};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::format as f;
+use std::path::Path;
+
+// Named entity templates, as objects of components like in entities.json
+// A prefab can `extends` another prefab, and override any of its components or single fields of
+// them. The prefabs are stored with their inheritance already resolved
+pub struct Prefabs(HashMap<String, Map<String, Value>>);
+
+impl Prefabs {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    // The components of a prefab, with overrides merged into them
+    pub fn components(
+        &self,
+        prefab_name: &str,
+        overrides: &Map<String, Value>,
+    ) -> anyhow::Result<Map<String, Value>> {
+        let mut components =
+            self.0.get(prefab_name).cloned().context(f!("no prefab `{prefab_name}`"))?;
+        merge_components(&mut components, overrides);
+        Ok(components)
+    }
+}
+
+// Adds the components of a prefab (with overrides) to an existing entity
+// Components the entity already has are replaced
+pub fn add_prefab_components(
+    ecs: &mut Ecs,
+    id: EntityId,
+    prefab_name: &str,
+    overrides: &Map<String, Value>,
+) -> anyhow::Result<()> {
+    let components = ecs.resource::<Prefabs>().components(prefab_name, overrides)?;
+    for (key, val) in &components {
+        ecs.add_component_with_name(id, key, val)?;
+    }
+    Ok(())
+}
+
+pub fn load_prefabs_from_file<P>(path: P) -> Prefabs
+where
+    P: AsRef<Path>,
+{
+    let Ok(json) = std::fs::read_to_string(&path) else {
+        log::error!("Couldn't read file `{}`", path.as_ref().to_string_lossy());
+        return Prefabs::new();
+    };
+
+    let r: anyhow::Result<Prefabs> = try {
+        let prefabs_value: Value = serde_json::from_str(&json).map_err(anyhow::Error::from)?;
+        let unresolved = prefabs_value.as_object().context("not an object")?;
+
+        let mut prefabs = Prefabs::new();
+        for name in unresolved.keys() {
+            match resolve_prefab(name, unresolved, &mut Vec::new()) {
+                Ok(components) => {
+                    prefabs.0.insert(name.clone(), components);
+                }
+                Err(e) => log::error!("Invalid prefab `{name}` (err: {e})"),
+            }
+        }
+        prefabs
+    };
+    r.unwrap_or_else(|e| {
+        log::error!("Invalid prefabs JSON file `{}` (err: {e})", path.as_ref().to_string_lossy());
+        Prefabs::new()
+    })
+}
+
+// Merges a prefab's own components over everything it extends
+fn resolve_prefab<'a>(
+    name: &'a str,
+    unresolved: &'a Map<String, Value>,
+    // Prefabs currently being resolved, to catch cycles
+    resolving: &mut Vec<&'a str>,
+) -> anyhow::Result<Map<String, Value>> {
+    if resolving.contains(&name) {
+        return Err(anyhow!("prefab `{name}` extends itself"));
+    }
+    resolving.push(name);
+
+    let prefab = unresolved
+        .get(name)
+        .context(f!("no prefab `{name}`"))?
+        .as_object()
+        .context(f!("prefab `{name}` not an object"))?;
+
+    let mut components = match prefab.get("extends") {
+        Some(Value::String(base)) => resolve_prefab(base, unresolved, resolving)?,
+        Some(_) => return Err(anyhow!("`extends` of prefab `{name}` not a string")),
+        None => Map::new(),
+    };
+    let own_components =
+        prefab.iter().filter(|(k, _)| *k != "extends").map(|(k, v)| (k.clone(), v.clone()));
+    merge_components(&mut components, &own_components.collect());
+
+    resolving.pop();
+    Ok(components)
+}
+
+// Objects are merged field by field, so that an override only needs the fields that differ
+// Anything else (including arrays) is replaced entirely
+fn merge_components(base: &mut Map<String, Value>, overrides: &Map<String, Value>) {
+    for (key, val) in overrides {
+        match (base.get_mut(key), val) {
+            (Some(Value::Object(base_object)), Value::Object(override_object)) => {
+                merge_components(base_object, override_object)
+            }
+            _ => {
+                base.insert(key.clone(), val.clone());
+            }
+        }
+    }
+}
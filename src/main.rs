@@ -1,26 +1,34 @@
 #![feature(try_blocks)]
+#![cfg_attr(test, feature(test))]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod components;
 mod data;
 mod dev_ui;
 mod ecs;
+mod events;
 mod input;
 mod loader;
 mod math;
 mod misc;
+mod pathfinding;
 mod render;
+mod save;
+mod schedule;
 mod script;
+mod spatial;
 mod update;
 mod world;
 
-use crate::misc::{LOGGER, WINDOW_SIZE};
+use crate::misc::{LOGGER, Musics, PlayerMovementLocked, Running, SoundEffects, WINDOW_SIZE};
 use crate::script::ScriptManager;
 use crate::script::console::ConsoleCommandExecutor;
+use crate::spatial::SpatialIndex;
 use dev_ui::DevUi;
 use ecs::Ecs;
 use misc::StoryVars;
 use render::renderer::Renderer;
+use schedule::{Scheduler, UpdateContext};
 use sdl2::mixer::{AUDIO_S16SYS, DEFAULT_CHANNELS};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
@@ -29,7 +37,6 @@ use world::{Map, World};
 pub struct GameData {
     pub world: World,
     pub ecs: Ecs,
-    pub story_vars: StoryVars,
     pub auto_scripts: Vec<String>,
 }
 
@@ -78,50 +85,66 @@ fn main() {
 
     sdl2::mixer::open_audio(41_100, AUDIO_S16SYS, DEFAULT_CHANNELS, 512).unwrap();
     sdl2::mixer::allocate_channels(10);
-    let sound_effects = loader::load_sound_effects();
-    let musics = loader::load_musics();
 
-    let ldtk_project: loader::ldtk_project::Project =
-        serde_json::from_str(&std::fs::read_to_string("data/world.ldtk").unwrap()).unwrap();
+    let ldtk_project = loader::load_ldtk_project("data/world.ldtk").unwrap();
 
-    let mut world = World::new();
+    let mut world = World::new(ldtk_project.default_grid_size as u32);
+    world.load_tile_animations(&ldtk_project.defs);
     for ldtk_world in &ldtk_project.worlds {
         // If world has level called "_world_map", then entire world is a single map
         // Otherwise, each level in the world is an individual map
         if ldtk_world.levels.iter().any(|l| l.identifier == "_world_map") {
-            world.maps.insert(ldtk_world.identifier.clone(), Map::from_ldtk_world(ldtk_world));
+            world.maps.insert(
+                ldtk_world.identifier.clone(),
+                Map::from_ldtk_world(ldtk_world, &ldtk_project.defs, world.cell_size),
+            );
         } else {
             for level in &ldtk_world.levels {
-                world.maps.insert(level.identifier.clone(), Map::from_ldtk_level(level));
+                world.maps.insert(
+                    level.identifier.clone(),
+                    Map::from_ldtk_level(level, &ldtk_project.defs, world.cell_size),
+                );
             }
         }
     }
 
     let mut ecs = Ecs::new();
+
+    let mut story_vars = StoryVars(HashMap::new());
+    loader::load_story_vars_from_file(&mut story_vars, "data/story_vars.json");
+
+    ecs.insert_resource(story_vars);
+    ecs.insert_resource(UiData {
+        message_window: None,
+        // TODO cutscene border
+        // TODO map overlay
+    });
+    ecs.insert_resource(PlayerMovementLocked(false));
+    ecs.insert_resource(Running(true));
+    ecs.insert_resource(SoundEffects(loader::load_sound_effects()));
+    ecs.insert_resource(Musics(loader::load_musics()));
+    ecs.insert_resource(SpatialIndex::new());
+
+    // Prefabs are needed by every way of loading entities
+    ecs.insert_resource(loader::prefabs::load_prefabs_from_file("data/prefabs.json"));
+
     // Load in order of ldtk > file > source, so that entities defined in previous steps may be
     // extended by components defined in following steps
     loader::ldtk_entities::load_entities_from_ldtk(&mut ecs, &ldtk_project);
     loader::load_entities_from_file(&mut ecs, "data/entities.json");
-    data::load_entities_from_source(&mut ecs);
-
-    let mut story_vars = StoryVars(HashMap::new());
-    loader::load_story_vars_from_file(&mut story_vars, "data/story_vars.json");
+    data::load_entities_from_source(&mut ecs, world.cell_size);
 
     let auto_scripts = vec![
         script::read_script_from_file("data/scripts.lua", "start").unwrap(),
         script::read_script_from_file("data/scripts.lua", "bakery_girl::panic").unwrap(),
     ];
 
-    let mut game_data = GameData { world, ecs, story_vars, auto_scripts };
+    let mut game_data = GameData { world, ecs, auto_scripts };
 
-    let mut ui_data = UiData {
-        message_window: None,
-        // TODO cutscene border
-        // TODO map overlay
-    };
     let mut script_manager = ScriptManager::new();
     let mut console_command_executor = ConsoleCommandExecutor::new();
-    let mut player_movement_locked = false;
+    let mut scheduler = Scheduler::new();
+    update::add_systems(&mut scheduler);
 
     // Scratchpad
     {}
@@ -133,36 +156,27 @@ fn main() {
     let mut last_time = Instant::now();
     // Pre-sleep duration of last frame as a percent of a full 60fps frame
     let mut frame_duration: f32 = 0.;
-    let mut running = true;
-    while running {
+    while game_data.ecs.resource::<Running>().0 {
         let delta = last_time.elapsed();
         last_time = Instant::now();
 
-        #[rustfmt::skip]
-        input::process_input(
-            &mut game_data, &mut event_pump, &mut running, &mut ui_data.message_window,
-            player_movement_locked, &mut dev_ui, &mut script_manager
-        );
+        input::process_input(&mut game_data, &mut event_pump, &mut dev_ui, &mut script_manager);
 
         #[rustfmt::skip]
         dev_ui.run(
-            &start_time, frame_duration, &mut game_data.ecs, &mut game_data.story_vars,
-            &script_manager, &mut console_command_executor
+            &start_time, frame_duration, &mut game_data.ecs, &script_manager,
+            &mut console_command_executor, &mut scheduler
         );
 
-        #[rustfmt::skip]
-        console_command_executor.execute(
-            &mut game_data, &mut ui_data, &mut player_movement_locked, &mut running,
-            &musics, &sound_effects,
-        );
+        console_command_executor.execute(&mut game_data, &mut script_manager);
 
-        #[rustfmt::skip]
-        update::update(
-            &mut game_data, &mut ui_data, &mut script_manager, &mut player_movement_locked,
-            &mut running, &musics, &sound_effects, delta
-        );
+        scheduler.run(&mut UpdateContext {
+            game_data: &mut game_data,
+            script_manager: &mut script_manager,
+            delta,
+        });
 
-        renderer.render(&game_data.world, &game_data.ecs, &ui_data, &mut dev_ui);
+        renderer.render(&game_data.world, &game_data.ecs, &mut dev_ui);
 
         frame_duration = last_time.elapsed().as_secs_f32() / (1. / 60.) * 100.;
         std::thread::sleep(Duration::from_secs_f32(1. / 60.).saturating_sub(last_time.elapsed()));
@@ -1,4 +1,3 @@
-use crate::misc::CELL_SIZE;
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
@@ -10,7 +9,6 @@ pub struct CellUnits;
 pub struct PixelUnits;
 
 pub type MapPos = Vec2<f64, MapUnits>;
-pub type CellPos = Vec2<i32, CellUnits>;
 
 // Vec2
 
@@ -49,6 +47,12 @@ impl<T: Clone, U> Clone for Vec2<T, U> {
 
 impl<T: Copy, U> Copy for Vec2<T, U> {}
 
+impl<T: PartialEq, U> PartialEq for Vec2<T, U> {
+    fn eq(&self, other: &Self) -> bool {
+        self.x == other.x && self.y == other.y
+    }
+}
+
 impl<T: Add, U> Add for Vec2<T, U> {
     type Output = Vec2<T::Output, U>;
 
@@ -170,6 +174,15 @@ impl<T: Clone, U> Clone for Rect<T, U> {
 
 impl<T: Copy, U> Copy for Rect<T, U> {}
 
+impl<T: PartialEq, U> PartialEq for Rect<T, U> {
+    fn eq(&self, other: &Self) -> bool {
+        self.x == other.x
+            && self.y == other.y
+            && self.width == other.width
+            && self.height == other.height
+    }
+}
+
 impl<T: Copy + Add<Output = T>, U> Rect<T, U> {
     pub fn left(&self) -> T {
         self.x
@@ -198,10 +211,10 @@ impl Vec2<f64, MapUnits> {
     // If I render directly onto the surface instead of onto an intermediate camera buffer that is
     // scaled later, then these pixel values will be a bit inaccurate, since they still need to be
     // multiplied by the render scale
-    pub fn to_pixel_units(self) -> Vec2<i32, PixelUnits> {
+    pub fn to_pixel_units(self, cell_size: u32) -> Vec2<i32, PixelUnits> {
         Vec2::new(
-            (self.x * CELL_SIZE as f64).floor() as i32,
-            (self.y * CELL_SIZE as f64).floor() as i32,
+            (self.x * cell_size as f64).floor() as i32,
+            (self.y * cell_size as f64).floor() as i32,
         )
     }
 
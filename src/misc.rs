@@ -1,14 +1,15 @@
 use crate::math::{MapPos, MapUnits, PixelUnits, Vec2};
 use colored::*;
+use derived_deref::Deref;
 use log::kv::Key;
 use log::{Level, Metadata, Record};
+use sdl2::mixer::{Chunk, Music};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::{LazyLock, Mutex};
 use tap::TapOptional;
 
 pub const WINDOW_SIZE: Vec2<u32, PixelUnits> = Vec2::new(1920, 1080);
-pub const CELL_SIZE: u32 = 16;
 
 // Fallible version of Regex::replace_all mostly copy pasted from the docs
 pub fn try_replace_all(
@@ -90,7 +91,7 @@ impl Aabb {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
 pub enum Direction {
     Up,
     #[default]
@@ -99,6 +100,20 @@ pub enum Direction {
     Right,
 }
 
+impl Direction {
+    // The direction to walk in a straight line between two positions along either axis
+    pub fn towards(from: MapPos, to: MapPos) -> Self {
+        let offset = to - from;
+        if offset.x.abs() > offset.y.abs() {
+            if offset.x < 0. { Direction::Left } else { Direction::Right }
+        } else if offset.y < 0. {
+            Direction::Up
+        } else {
+            Direction::Down
+        }
+    }
+}
+
 pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new());
 
 pub struct Logger {
@@ -168,6 +183,19 @@ impl From<&Record<'_>> for LogRecordOwned {
     }
 }
 
+// Resources
+
+pub struct PlayerMovementLocked(pub bool);
+
+// Set to false to close the game at the end of the frame
+pub struct Running(pub bool);
+
+#[derive(Deref)]
+pub struct SoundEffects(pub HashMap<String, Chunk>);
+
+#[derive(Deref)]
+pub struct Musics(pub HashMap<String, Music<'static>>);
+
 pub struct StoryVars(pub HashMap<String, i32>);
 
 impl StoryVars {
@@ -0,0 +1,392 @@
+use crate::components::{Collision, Position};
+use crate::ecs::{Ecs, EntityId};
+use crate::math::{MapPos, MapUnits, Vec2};
+use crate::misc::{Aabb, Direction};
+use crate::spatial::SpatialIndex;
+use crate::world::Map;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+// Most nodes searched before giving up, so that unreachable destinations on big maps don't
+// freeze the game
+const MAX_SEARCHED_NODES: usize = 50_000;
+
+// Turning costs extra, so that paths are a few long straight walks rather than a staircase
+const STEP_COST: u32 = 2;
+const TURN_COST: u32 = 1;
+
+const DIRECTIONS: [(Direction, (i32, i32)); 4] = [
+    (Direction::Up, (0, -1)),
+    (Direction::Down, (0, 1)),
+    (Direction::Left, (-1, 0)),
+    (Direction::Right, (1, 0)),
+];
+
+type Node = (i32, i32);
+// A node and the index of the direction it was entered in (None for the start)
+type State = (Node, Option<usize>);
+
+// A* for an entity's hitbox over the collision grid of a map, at twice its resolution (the
+// centers and corners of collision cells), so that hitboxes fit through gaps as wide as them
+// Solid entities other than the walking one are obstacles too, where they are now
+// Returns the waypoints of the path, each in a straight line from the previous one (the first
+// from start), ending at goal
+pub fn find_path(
+    ecs: &Ecs,
+    map: &Map,
+    entity: EntityId,
+    hitbox: Vec2<f64, MapUnits>,
+    start: MapPos,
+    goal: MapPos,
+) -> Option<Vec<MapPos>> {
+    let spacing = map.collision_cell_size / 2.;
+    let origin = map.offset.to_map_units();
+    let columns = (map.dimensions.x as f64 / spacing).round() as i32;
+    let rows = (map.dimensions.y as f64 / spacing).round() as i32;
+
+    let node_pos =
+        |node: Node| origin + Vec2::new(node.0 as f64 * spacing, node.1 as f64 * spacing);
+    let in_bounds = |node: Node| node.0 >= 0 && node.0 <= columns && node.1 >= 0 && node.1 <= rows;
+    // The nodes at the corners of the grid square containing pos
+    let nodes_around = |pos: MapPos| -> Vec<Node> {
+        let x = (pos.x - origin.x) / spacing;
+        let y = (pos.y - origin.y) / spacing;
+        let mut nodes = vec![
+            (x.floor() as i32, y.floor() as i32),
+            (x.ceil() as i32, y.floor() as i32),
+            (x.floor() as i32, y.ceil() as i32),
+            (x.ceil() as i32, y.ceil() as i32),
+        ];
+        nodes.sort();
+        nodes.dedup();
+        nodes.retain(|node| in_bounds(*node));
+        nodes
+    };
+
+    let spatial_index = ecs.resource::<SpatialIndex>();
+    let blocked = |aabb: &Aabb, velocity: Vec2<f64, MapUnits>| {
+        let blocked_by_map = map
+            .collision_aabbs_in_region(aabb)
+            .any(|(cell_aabb, kind)| kind.blocks(velocity) && cell_aabb.intersects(aabb));
+        let blocked_by_entity =
+            spatial_index.query_region(&map.name, aabb).into_iter().any(|other_id| {
+                other_id != entity
+                    && ecs.query_one_with_id::<(&Position, &Collision)>(other_id).is_some_and(
+                        |(position, collision)| {
+                            collision.solid
+                                && Aabb::new(position.map_pos, collision.hitbox).intersects(aabb)
+                        },
+                    )
+            });
+        blocked_by_map || blocked_by_entity
+    };
+    let walkable = |node: Node, direction: Direction| {
+        let velocity = match direction {
+            Direction::Up => Vec2::new(0., -1.),
+            Direction::Down => Vec2::new(0., 1.),
+            Direction::Left => Vec2::new(-1., 0.),
+            Direction::Right => Vec2::new(1., 0.),
+        };
+        !blocked(&Aabb::new(node_pos(node), hitbox), velocity)
+    };
+    // Whether the hitbox can walk straight from one point to another along one axis
+    // (Checked over the whole area it sweeps)
+    let clear = |from: MapPos, to: MapPos| {
+        if from == to {
+            return true;
+        }
+        let (a, b) = (Aabb::new(from, hitbox), Aabb::new(to, hitbox));
+        let swept = Aabb {
+            top: a.top.min(b.top),
+            bottom: a.bottom.max(b.bottom),
+            left: a.left.min(b.left),
+            right: a.right.max(b.right),
+        };
+        !blocked(&swept, to - from)
+    };
+    // The start and goal aren't on nodes, so they're joined to the path with a corner, to keep
+    // walks straight
+    let corner_between = |from: MapPos, to: MapPos| {
+        [Vec2::new(to.x, from.y), Vec2::new(from.x, to.y)]
+            .into_iter()
+            .find(|corner| clear(from, *corner) && clear(*corner, to))
+    };
+    let join_cost = |from: MapPos, to: MapPos| {
+        (((from.x - to.x).abs() + (from.y - to.y).abs()) / spacing * STEP_COST as f64).round()
+            as u32
+    };
+
+    // No need to search when there's nothing in the way
+    if let Some(corner) = corner_between(start, goal) {
+        return Some(straight_waypoints([start, corner, goal].into_iter()));
+    }
+
+    // Any node around the start or goal that can be joined to it
+    // (The nearest node might be blocked, such as when the entity is against a wall)
+    let start_corners: HashMap<Node, MapPos> = nodes_around(start)
+        .into_iter()
+        .filter_map(|node| corner_between(start, node_pos(node)).map(|c| (node, c)))
+        .collect();
+    let goal_corners: HashMap<Node, MapPos> = nodes_around(goal)
+        .into_iter()
+        .filter_map(|node| corner_between(node_pos(node), goal).map(|c| (node, c)))
+        .collect();
+    if start_corners.is_empty() || goal_corners.is_empty() {
+        return None;
+    }
+
+    let heuristic = |node: Node| {
+        goal_corners
+            .keys()
+            .map(|goal_node| {
+                ((node.0 - goal_node.0).abs() + (node.1 - goal_node.1).abs()) as u32 * STEP_COST
+            })
+            .min()
+            .unwrap_or(0)
+    };
+
+    let mut costs: HashMap<State, u32> = HashMap::new();
+    let mut came_from: HashMap<State, State> = HashMap::new();
+    let mut walkable_cache: HashMap<(Node, usize), bool> = HashMap::new();
+    let mut open = BinaryHeap::new();
+    for node in start_corners.keys() {
+        let cost = join_cost(start, node_pos(*node));
+        costs.insert((*node, None), cost);
+        open.push(Reverse((cost + heuristic(*node), cost, (*node, None))));
+    }
+    let mut searched = 0;
+
+    let goal_state = loop {
+        let Reverse((_, cost, state)) = open.pop()?;
+        // (Already reached more cheaply since it was queued)
+        if cost > costs[&state] {
+            continue;
+        }
+        if goal_corners.contains_key(&state.0) {
+            break state;
+        }
+
+        searched += 1;
+        if searched > MAX_SEARCHED_NODES {
+            return None;
+        }
+
+        for (i, (direction, (dx, dy))) in DIRECTIONS.iter().enumerate() {
+            let next = (state.0.0 + dx, state.0.1 + dy);
+            if !in_bounds(next)
+                || !*walkable_cache.entry((next, i)).or_insert_with(|| walkable(next, *direction))
+            {
+                continue;
+            }
+
+            let turn_cost = if state.1.is_some_and(|d| d != i) { TURN_COST } else { 0 };
+            let next_cost = cost + STEP_COST + turn_cost;
+            let next_state = (next, Some(i));
+            if costs.get(&next_state).is_none_or(|c| next_cost < *c) {
+                costs.insert(next_state, next_cost);
+                came_from.insert(next_state, state);
+                open.push(Reverse((next_cost + heuristic(next), next_cost, next_state)));
+            }
+        }
+    };
+
+    let mut nodes = vec![goal_state.0];
+    let mut state = goal_state;
+    while let Some(previous) = came_from.get(&state) {
+        nodes.push(previous.0);
+        state = *previous;
+    }
+    nodes.reverse();
+
+    let first_corner = start_corners[&nodes[0]];
+    let last_corner = goal_corners[&goal_state.0];
+    let points = [start, first_corner]
+        .into_iter()
+        .chain(nodes.into_iter().map(node_pos))
+        .chain([last_corner, goal]);
+    Some(straight_waypoints(points))
+}
+
+// Only the points where a path turns (and its end), without its start
+fn straight_waypoints(points: impl Iterator<Item = MapPos>) -> Vec<MapPos> {
+    let mut points = points.collect::<Vec<_>>();
+    points.dedup();
+
+    let mut waypoints: Vec<MapPos> = Vec::new();
+    for (i, point) in points.iter().enumerate().skip(1) {
+        let straight = points.get(i + 1).is_some_and(|next| {
+            let previous = points[i - 1];
+            (previous.x == point.x && point.x == next.x)
+                || (previous.y == point.y && point.y == next.y)
+        });
+        if !straight {
+            waypoints.push(*point);
+        }
+    }
+    waypoints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_path, straight_waypoints};
+    use crate::ecs::{Ecs, EntityId};
+    use crate::math::{MapPos, MapUnits, Vec2};
+    use crate::misc::{Aabb, Direction};
+    use crate::spatial::SpatialIndex;
+    use crate::world::{CollisionKind, Map, MapProperties};
+
+    // A map with cells of size 1 from rows of '.' (empty), '#' (solid) and '^', 'v', '<', '>'
+    // (ledges)
+    fn grid(rows: &[&str]) -> Map {
+        let collisions: Vec<Option<CollisionKind>> = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '#' => Some(CollisionKind::Solid),
+                '^' => Some(CollisionKind::Ledge(Direction::Up)),
+                'v' => Some(CollisionKind::Ledge(Direction::Down)),
+                '<' => Some(CollisionKind::Ledge(Direction::Left)),
+                '>' => Some(CollisionKind::Ledge(Direction::Right)),
+                _ => None,
+            })
+            .collect();
+        let columns = rows[0].len() as i32;
+        Map {
+            name: "map".to_string(),
+            dimensions: Vec2::new(columns, rows.len() as i32),
+            offset: Vec2::new(0, 0),
+            tile_layers: Vec::new(),
+            collisions,
+            collision_cell_size: 1.,
+            collision_columns: columns,
+            properties: MapProperties::default(),
+        }
+    }
+
+    fn path(
+        map: &Map,
+        hitbox: Vec2<f64, MapUnits>,
+        start: MapPos,
+        goal: MapPos,
+    ) -> Option<Vec<MapPos>> {
+        let mut ecs = Ecs::new();
+        ecs.insert_resource(SpatialIndex::new());
+        let entity: EntityId = ecs.add_entity();
+        let waypoints = find_path(&ecs, map, entity, hitbox, start, goal)?;
+
+        // Every walk is straight and doesn't go through walls
+        let mut from = start;
+        for to in &waypoints {
+            assert!(from.x == to.x || from.y == to.y, "{from:?} to {to:?} isn't straight");
+            let (a, b) = (Aabb::new(from, hitbox), Aabb::new(*to, hitbox));
+            let swept = Aabb {
+                top: a.top.min(b.top),
+                bottom: a.bottom.max(b.bottom),
+                left: a.left.min(b.left),
+                right: a.right.max(b.right),
+            };
+            assert!(
+                !map.collision_aabbs_in_region(&swept)
+                    .any(|(cell, kind)| kind == CollisionKind::Solid && cell.intersects(&swept)),
+                "{from:?} to {to:?} goes through a wall"
+            );
+            from = *to;
+        }
+        Some(waypoints)
+    }
+
+    #[test]
+    fn blocked_goal_has_no_path() {
+        let map = grid(&[".....", ".###.", ".#.#.", ".###.", "....."]);
+        let hitbox = Vec2::new(0.5, 0.5);
+        let start = Vec2::new(0.5, 0.5);
+
+        // Inside a wall
+        assert_eq!(path(&map, hitbox, start, Vec2::new(1.5, 1.5)), None);
+        // Walled in
+        assert_eq!(path(&map, hitbox, start, Vec2::new(2.5, 2.5)), None);
+        assert!(path(&map, hitbox, start, Vec2::new(4.5, 4.5)).is_some());
+    }
+
+    #[test]
+    fn ledges_are_only_crossed_in_their_direction() {
+        let hitbox = Vec2::new(0.5, 0.5);
+        let top = Vec2::new(2.5, 0.5);
+        let bottom = Vec2::new(2.5, 2.5);
+        let left = Vec2::new(0.5, 1.5);
+        let right = Vec2::new(4.5, 1.5);
+
+        for (rows, from, to) in [
+            ([".....", "vvvvv", "....."], top, bottom),
+            ([".....", "^^^^^", "....."], bottom, top),
+            (["..>..", "..>..", "..>.."], left, right),
+            (["..<..", "..<..", "..<.."], right, left),
+        ] {
+            let map = grid(&rows);
+            assert_eq!(path(&map, hitbox, from, to), Some(vec![to]), "{rows:?}");
+            assert_eq!(path(&map, hitbox, to, from), None, "{rows:?}");
+        }
+    }
+
+    #[test]
+    fn fits_through_gap_as_wide_as_hitbox() {
+        let map = grid(&[".....", "##.##", "....."]);
+        let start = Vec2::new(2.5, 0.5);
+        let goal = Vec2::new(2.5, 2.5);
+
+        assert_eq!(path(&map, Vec2::new(1., 1.), start, goal), Some(vec![goal]));
+        assert_eq!(path(&map, Vec2::new(1.25, 1.), start, goal), None);
+
+        // Lined up with the gap to go through it
+        assert_eq!(
+            path(&map, Vec2::new(1., 1.), Vec2::new(0.5, 0.5), Vec2::new(0.5, 2.5)),
+            Some(vec![Vec2::new(2.5, 0.5), Vec2::new(2.5, 2.5), Vec2::new(0.5, 2.5)])
+        );
+    }
+
+    #[test]
+    fn start_and_goal_are_joined_around_walls() {
+        let map = grid(&["#....", "#.##.", "#.#.."]);
+        let hitbox = Vec2::new(0.4375, 0.4375);
+
+        // Against a wall, where the nearest node is blocked
+        let start = Vec2::new(1.21875, 0.5);
+        let waypoints = path(&map, hitbox, start, Vec2::new(3.5, 2.5)).unwrap();
+        assert!(waypoints.iter().all(|waypoint| waypoint.x >= start.x), "{waypoints:?}");
+
+        // Between walls, where the nearest node is in one
+        let start = Vec2::new(1.75, 2.25);
+        let goal = Vec2::new(4.5, 2.5);
+        assert!(path(&map, hitbox, start, goal).is_some());
+        assert!(path(&map, hitbox, goal, start).is_some());
+    }
+
+    #[test]
+    fn waypoints_are_where_the_path_turns() {
+        let map = grid(&["....", "....", "...."]);
+        let hitbox = Vec2::new(0.5, 0.5);
+        let start = Vec2::new(0.5, 0.5);
+
+        let goal = Vec2::new(3.5, 0.5);
+        assert_eq!(path(&map, hitbox, start, goal), Some(vec![goal]));
+
+        let goal = Vec2::new(3.5, 2.5);
+        let waypoints = path(&map, hitbox, start, goal).unwrap();
+        assert_eq!(waypoints.len(), 2, "{waypoints:?}");
+        assert_eq!(waypoints[1], goal);
+
+        // Off the node grid
+        let start = Vec2::new(0.3, 0.6);
+        let goal = Vec2::new(3.3, 0.6);
+        assert_eq!(path(&map, hitbox, start, goal), Some(vec![goal]));
+    }
+
+    #[test]
+    fn straight_waypoints_merges_repeated_and_collinear_points() {
+        let points = [(0., 0.), (0., 0.), (1., 0.), (2., 0.), (2., 1.), (2., 1.), (2., 3.)];
+        assert_eq!(
+            straight_waypoints(points.into_iter().map(|(x, y)| Vec2::new(x, y))),
+            vec![Vec2::new(2., 0.), Vec2::new(2., 3.)]
+        );
+    }
+}
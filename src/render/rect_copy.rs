@@ -90,23 +90,40 @@ impl RectCopyPipeline {
         dest_y: i32,
         dest_w: u32,
         dest_h: u32,
+        flip_x: bool,
+        flip_y: bool,
+        alpha: f32,
     ) {
         let src_tex_w = src_texture.size.0 as f32;
         let src_tex_h = src_texture.size.1 as f32;
         let target_w = render_target_size.0 as f32;
         let target_h = render_target_size.1 as f32;
 
+        // Map pixel coords to 0to1 tex coords
+        let (mut src_top, mut src_bottom) =
+            (src_y as f32 / src_tex_h, (src_y + src_h) as f32 / src_tex_h);
+        let (mut src_left, mut src_right) =
+            (src_x as f32 / src_tex_w, (src_x + src_w) as f32 / src_tex_w);
+
+        // Flipping is copying from the opposite sides
+        if flip_x {
+            std::mem::swap(&mut src_left, &mut src_right);
+        }
+        if flip_y {
+            std::mem::swap(&mut src_top, &mut src_bottom);
+        }
+
         let params = RectCopyParams {
-            // Map pixel coords to 0to1 tex coords
-            src_top: src_y as f32 / src_tex_h,
-            src_left: src_x as f32 / src_tex_w,
-            src_bottom: (src_y + src_h) as f32 / src_tex_h,
-            src_right: (src_x + src_w) as f32 / src_tex_w,
+            src_top,
+            src_left,
+            src_bottom,
+            src_right,
             // Map pixel coords to 0to1, invert Y, and map to -1to1 clip space coords
             dest_top: (dest_y as f32 / target_h).pipe(|x| 1. - x) * 2. - 1.,
             dest_left: (dest_x as f32 / target_w) * 2. - 1.,
             dest_bottom: ((dest_y + dest_h as i32) as f32 / target_h).pipe(|x| 1. - x) * 2. - 1.,
             dest_right: ((dest_x + dest_w as i32) as f32 / target_w) * 2. - 1.,
+            alpha,
         };
 
         render_pass.set_pipeline(&self.pipeline);
@@ -128,6 +145,7 @@ struct RectCopyParams {
     dest_left: f32,
     dest_bottom: f32,
     dest_right: f32,
+    alpha: f32,
 }
 unsafe impl Pod for RectCopyParams {}
 unsafe impl Zeroable for RectCopyParams {}
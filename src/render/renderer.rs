@@ -1,11 +1,10 @@
-use crate::components::{Camera, Position, SineOffsetAnimation, SpriteComp};
+use crate::components::{Camera, Position, SineOffsetAnimation, SpriteComp, Teleporting};
 use crate::data::CAMERA_ENTITY_NAME;
 use crate::ecs::Ecs;
-use crate::math::{CellPos, CellUnits, MapPos, MapUnits, PixelUnits, Rect, Vec2};
-use crate::misc::CELL_SIZE;
+use crate::math::{MapPos, MapUnits, PixelUnits, Rect, Vec2};
 use crate::render::rect_copy::RectCopyPipeline;
 use crate::render::rect_fill::RectFillPipeline;
-use crate::world::{Map, TileLayer, World};
+use crate::world::{LayerDepth, Map, TileLayer, World};
 use crate::{DevUi, MessageWindow, UiData};
 use egui::TexturesDelta;
 use image::GenericImageView;
@@ -73,7 +72,7 @@ impl Renderer<'_> {
                 // Push constants are only available on native. Can't target wasm.
                 required_features: Features::PUSH_CONSTANTS,
                 // Limits should be kept to exactly what we need and no more
-                required_limits: Limits { max_push_constant_size: 32, ..Default::default() },
+                required_limits: Limits { max_push_constant_size: 36, ..Default::default() },
                 memory_hints: MemoryHints::default(),
                 trace: Trace::Off,
             })
@@ -174,7 +173,6 @@ impl Renderer<'_> {
         &mut self,
         world: &World,
         ecs: &Ecs,
-        ui_data: &UiData,
         // &mut cause we need to consume full_output.textures_delta
         dev_ui: &mut DevUi,
     ) {
@@ -187,8 +185,12 @@ impl Renderer<'_> {
             self.device.create_command_encoder(&CommandEncoderDescriptor { label: None });
 
         // Does the camera texture have to be recreated every frame? Can I save and reuse it?
-        let camera_texture = ecs.query_one_with_name::<&Camera>(CAMERA_ENTITY_NAME).map(|camera| {
-            self.prepare_camera_texture(camera.size, surface_texture.texture.format())
+        let camera_texture = ecs.query_one::<&Camera>(CAMERA_ENTITY_NAME).map(|camera| {
+            self.prepare_camera_texture(
+                camera.size,
+                world.cell_size,
+                surface_texture.texture.format(),
+            )
         });
 
         // Camera render pass
@@ -241,9 +243,27 @@ impl Renderer<'_> {
                     0,
                     surface_size.0,
                     surface_size.1,
+                    false,
+                    false,
+                    1.,
+                );
+            }
+
+            // Fade to black while teleporting
+            let fade_alpha = ecs.query::<&Teleporting>().map(|t| t.fade_alpha()).fold(0., f64::max);
+            if fade_alpha > 0. {
+                self.rect_fill_pipeline.execute(
+                    &mut render_pass,
+                    surface_size,
+                    0,
+                    0,
+                    surface_size.0,
+                    surface_size.1,
+                    [0., 0., 0., fade_alpha as f32],
                 );
             }
 
+            let ui_data = ecs.resource::<UiData>();
             self.draw_message_window(&mut render_pass, surface_size, &ui_data.message_window);
         }
 
@@ -283,10 +303,11 @@ impl Renderer<'_> {
     fn prepare_camera_texture(
         &self,
         camera_size: Vec2<f64, MapUnits>,
+        cell_size: u32,
         surface_format: TextureFormat,
     ) -> Texture {
         let camera_texture_size =
-            ((camera_size.x * CELL_SIZE as f64) as u32, (camera_size.y * CELL_SIZE as f64) as u32);
+            ((camera_size.x * cell_size as f64) as u32, (camera_size.y * cell_size as f64) as u32);
         let camera_wgpu_texture = self.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
@@ -328,7 +349,7 @@ impl Renderer<'_> {
         ecs: &Ecs,
     ) {
         if let Some((camera_position, camera_component)) =
-            ecs.query_one_with_name::<(&Position, &Camera)>(CAMERA_ENTITY_NAME)
+            ecs.query_one::<(&Position, &Camera)>(CAMERA_ENTITY_NAME)
             && let Some(map) = world.maps.get(&camera_position.map).tap_none(
                 || log::error!(once = true; "Map doesn't exist: {}", &camera_position.map),
             )
@@ -341,16 +362,30 @@ impl Renderer<'_> {
             );
 
             // Draw tile layers below entities
-            for layer in map.tile_layers.iter().take_while_inclusive(|l| l.name != "interiors_3") {
-                self.draw_tile_layer(render_pass, render_target_size, layer, map, camera_rect);
+            for layer in map.tile_layers.iter().filter(|l| l.depth == LayerDepth::BelowEntities) {
+                self.draw_tile_layer(
+                    render_pass,
+                    render_target_size,
+                    layer,
+                    map,
+                    world,
+                    camera_rect,
+                );
             }
 
             // Draw entities
-            self.draw_entities(render_pass, render_target_size, ecs, map, camera_rect);
+            self.draw_entities(render_pass, render_target_size, ecs, map, world, camera_rect);
 
             // Draw tile layers above entities
-            for layer in map.tile_layers.iter().skip_while(|l| l.name != "exteriors_4") {
-                self.draw_tile_layer(render_pass, render_target_size, layer, map, camera_rect);
+            for layer in map.tile_layers.iter().filter(|l| l.depth == LayerDepth::AboveEntities) {
+                self.draw_tile_layer(
+                    render_pass,
+                    render_target_size,
+                    layer,
+                    map,
+                    world,
+                    camera_rect,
+                );
             }
         }
     }
@@ -361,6 +396,7 @@ impl Renderer<'_> {
         render_target_size: (u32, u32),
         layer: &TileLayer,
         map: &Map,
+        world: &World,
         camera_rect: Rect<f64, MapUnits>,
     ) {
         let Some(tileset) = self.tilesets.get(&layer.tileset_path) else {
@@ -368,42 +404,47 @@ impl Renderer<'_> {
             return;
         };
 
-        let tileset_width_in_tiles = tileset.size.0 / CELL_SIZE;
-
-        let map_bounds: Rect<i32, CellUnits> =
-            Rect::new(map.offset.x, map.offset.y, map.dimensions.x, map.dimensions.y);
-        for col in map_bounds.left()..map_bounds.right() {
-            for row in map_bounds.top()..map_bounds.bottom() {
-                let cell_pos = CellPos::new(col, row);
-                let vec_coords = cell_pos - map.offset;
-                let vec_index = vec_coords.y * map.dimensions.x + vec_coords.x;
-
-                if let Some(Some(tile_id)) = layer.tile_ids.get(vec_index as usize) {
-                    let top_left_in_viewport = map_pos_to_top_left_in_viewport(
-                        cell_pos.to_map_units(),
-                        Some(layer.offset),
-                        camera_rect,
-                    );
-
-                    let tile_y_in_tileset = (tile_id / tileset_width_in_tiles) * CELL_SIZE;
-                    let tile_x_in_tileset = (tile_id % tileset_width_in_tiles) * CELL_SIZE;
-
-                    self.rect_copy_pipeline.execute(
-                        render_pass,
-                        render_target_size,
-                        &self.sampler_bind_group,
-                        tileset,
-                        tile_x_in_tileset,
-                        tile_y_in_tileset,
-                        CELL_SIZE,
-                        CELL_SIZE,
-                        top_left_in_viewport.x,
-                        top_left_in_viewport.y,
-                        CELL_SIZE,
-                        CELL_SIZE,
-                    );
-                }
-            }
+        let tileset_width_in_tiles = tileset.size.0 / layer.tile_size;
+
+        for (i, tile) in layer.tiles.iter().enumerate() {
+            let Some(tile) = tile else {
+                continue;
+            };
+
+            // Layer cells can be of a different size than map cells
+            let col = i as i32 % layer.columns;
+            let row = i as i32 / layer.columns;
+            let map_pos = map.offset.to_map_units()
+                + Vec2::new(col as f64, row as f64) * layer.grid_size as f64
+                    / world.cell_size as f64;
+            let top_left_in_viewport = map_pos_to_top_left_in_viewport(
+                map_pos,
+                Some(layer.offset),
+                camera_rect,
+                world.cell_size,
+            );
+
+            let tile_id = world.animated_tile_id(&layer.tileset_path, tile.id);
+            let tile_y_in_tileset = (tile_id / tileset_width_in_tiles) * layer.tile_size;
+            let tile_x_in_tileset = (tile_id % tileset_width_in_tiles) * layer.tile_size;
+
+            self.rect_copy_pipeline.execute(
+                render_pass,
+                render_target_size,
+                &self.sampler_bind_group,
+                tileset,
+                tile_x_in_tileset,
+                tile_y_in_tileset,
+                layer.tile_size,
+                layer.tile_size,
+                top_left_in_viewport.x,
+                top_left_in_viewport.y,
+                layer.tile_size,
+                layer.tile_size,
+                tile.flip_x,
+                tile.flip_y,
+                tile.alpha * layer.opacity,
+            );
         }
     }
 
@@ -413,6 +454,7 @@ impl Renderer<'_> {
         render_target_size: (u32, u32),
         ecs: &Ecs,
         map: &Map,
+        world: &World,
         camera_rect: Rect<f64, MapUnits>,
     ) {
         for (position, sprite_component, sine_offset_animation) in
@@ -425,7 +467,7 @@ impl Renderer<'_> {
                 continue;
             }
 
-            if !sprite_component.visible {
+            if !sprite_component.visible || !sprite_component.parent_visible {
                 continue;
             }
 
@@ -450,8 +492,12 @@ impl Renderer<'_> {
                 position += offset;
             }
 
-            let top_left_in_viewport =
-                map_pos_to_top_left_in_viewport(position, Some(sprite.anchor * -1), camera_rect);
+            let top_left_in_viewport = map_pos_to_top_left_in_viewport(
+                position,
+                Some(sprite.anchor * -1),
+                camera_rect,
+                world.cell_size,
+            );
 
             self.rect_copy_pipeline.execute(
                 render_pass,
@@ -466,6 +512,9 @@ impl Renderer<'_> {
                 top_left_in_viewport.y,
                 sprite.rect.width,
                 sprite.rect.height,
+                false,
+                false,
+                1.,
             );
         }
     }
@@ -631,9 +680,10 @@ fn map_pos_to_top_left_in_viewport(
     map_pos: MapPos,
     sprite_offset: Option<Vec2<i32, PixelUnits>>,
     camera_rect: Rect<f64, MapUnits>,
+    cell_size: u32,
 ) -> Vec2<i32, PixelUnits> {
     let map_pos_relative_to_camera_top_left = map_pos - camera_rect.top_left();
-    let position_in_viewport = map_pos_relative_to_camera_top_left.to_pixel_units();
+    let position_in_viewport = map_pos_relative_to_camera_top_left.to_pixel_units(cell_size);
     let top_left_in_viewport = position_in_viewport + sprite_offset.unwrap_or_default();
     return top_left_in_viewport;
 }
@@ -1,6 +1,7 @@
 struct VertexOutput {
   @builtin(position) clip_position: vec4<f32>,
-  @location(0) tex_coords: vec2<f32>
+  @location(0) tex_coords: vec2<f32>,
+  @location(1) alpha: f32
 }
 
 struct RectCopyParams {
@@ -12,6 +13,7 @@ struct RectCopyParams {
   dest_left: f32,
   dest_bottom: f32,
   dest_right: f32,
+  alpha: f32,
 }
 
 var<push_constant> params: RectCopyParams;
@@ -43,6 +45,7 @@ fn vertex_main(
   var output: VertexOutput;
   output.clip_position = vec4f(position, 0.0, 1.0);
   output.tex_coords = tex_coords;
+  output.alpha = params.alpha;
 
   return output;
 }
@@ -52,5 +55,6 @@ fn vertex_main(
 
 @fragment
 fn fragment_main(input: VertexOutput) -> @location(0) vec4<f32> {
-  return textureSample(texture, sampler_, input.tex_coords);
+  let color = textureSample(texture, sampler_, input.tex_coords);
+  return vec4f(color.rgb, color.a * input.alpha);
 }
\ No newline at end of file
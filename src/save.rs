@@ -0,0 +1,149 @@
+use crate::components::{Camera, Position};
+use crate::ecs::{Ecs, EntityId, With};
+use crate::misc::{PlayerMovementLocked, StoryVars};
+use crate::script::ScriptManager;
+use crate::world::MapEdit;
+use crate::{GameData, UiData};
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Serialize};
+use slotmap::SlotMap;
+use std::collections::HashMap;
+use std::format as f;
+use std::path::Path;
+
+pub const QUICKSAVE_PATH: &str = "saves/quicksave.json";
+
+// Bump this whenever the save format changes in a way that old saves can't be loaded
+const SAVE_VERSION: u32 = 1;
+
+// The entity id slotmap is saved as a whole (including free slots and versions) so that every
+// EntityId is exactly the same after loading, and saved references to entities stay valid
+//
+// Only registered (serde) components are saved. Anything else is dropped on load on purpose:
+// - SineOffsetAnimation is a short-lived effect and is simply not restored
+// - SfxEmitter::channel is not saved, so the sfx is restarted from the beginning by
+//   update_sfx_emitting_entities
+// - AnimationComp::elapsed is not saved, so animations restart from their first frame
+//
+// Maps themselves aren't saved, only the edits made to them since they were loaded from LDtk
+#[derive(Serialize, Deserialize)]
+struct SaveData {
+    version: u32,
+    // The map the camera was on when saved
+    map: String,
+    entity_ids: SlotMap<EntityId, ()>,
+    entities: Vec<serde_json::Map<String, serde_json::Value>>,
+    story_vars: HashMap<String, i32>,
+    #[serde(default)]
+    map_edits: Vec<MapEdit>,
+}
+
+pub fn save_game<P>(game_data: &GameData, path: P) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let ecs = &game_data.ecs;
+
+    let map = ecs
+        .query::<(&Position, With<Camera>)>()
+        .next()
+        .map(|(position, _)| position.map.clone())
+        .context("no camera entity")?;
+
+    let entities = ecs
+        .entity_ids
+        .keys()
+        .map(|id| {
+            let mut entity = serde_json::Map::new();
+            entity.insert("EntityId".to_string(), serde_json::to_value(id).expect("is serde"));
+            if let serde_json::Value::Object(components) = ecs.save_components_to_value(id) {
+                entity.extend(components);
+            }
+            entity
+        })
+        .collect();
+
+    let save_data = SaveData {
+        version: SAVE_VERSION,
+        map,
+        entity_ids: ecs.entity_ids.clone(),
+        entities,
+        story_vars: ecs.resource::<StoryVars>().0.clone(),
+        map_edits: game_data.world.map_edits.clone(),
+    };
+
+    if let Some(dir) = path.as_ref().parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&save_data)?)
+        .with_context(|| f!("couldn't write file `{}`", path.as_ref().to_string_lossy()))?;
+
+    Ok(())
+}
+
+// Replaces the entire ecs (except for resources), story vars and map edits with the saved ones,
+// and stops whatever was in progress, since running scripts and messages aren't saved
+// Game data is left untouched if the save is invalid in any way
+pub fn load_game<P>(
+    game_data: &mut GameData,
+    script_manager: &mut ScriptManager,
+    path: P,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| f!("couldn't read file `{}`", path.as_ref().to_string_lossy()))?;
+    let save_data: SaveData = serde_json::from_str(&json)?;
+
+    if save_data.version != SAVE_VERSION {
+        return Err(anyhow!(
+            "unsupported save version {} (expected {SAVE_VERSION})",
+            save_data.version
+        ));
+    }
+
+    if !game_data.world.maps.contains_key(&save_data.map) {
+        return Err(anyhow!("map doesn't exist: {}", save_data.map));
+    }
+
+    let mut ecs = Ecs::new();
+    ecs.entity_ids = save_data.entity_ids;
+
+    for entity in &save_data.entities {
+        let id: EntityId = serde_json::from_value(
+            entity.get("EntityId").context("entity has no EntityId")?.clone(),
+        )?;
+        if !ecs.entity_ids.contains_key(id) {
+            return Err(anyhow!("EntityId not in saved entity ids: {id:?}"));
+        }
+
+        for (key, val) in entity.iter().filter(|(k, _)| *k != "EntityId") {
+            ecs.add_component_with_name(id, key, val)?;
+        }
+    }
+
+    // The camera carries on from the map it was on, so that MapChanged (which restarts the music
+    // and queues the on_enter_script) is only sent if the loaded game is on another map
+    let previous_map = game_data.ecs.query::<&Camera>().next().and_then(|c| c.previous_map.clone());
+    if let Some(mut camera) = ecs.query::<&mut Camera>().next() {
+        camera.previous_map = previous_map;
+    }
+
+    // (Maps are left untouched if any saved edit is invalid, so this is the last step that can
+    // fail)
+    game_data.world.replace_map_edits(save_data.map_edits)?;
+
+    // Run on_remove hooks for the old entities (such as stopping their sounds)
+    game_data.ecs.remove_all_entities();
+
+    ecs.take_resources_from(&mut game_data.ecs);
+    ecs.resource_mut::<StoryVars>().0 = save_data.story_vars;
+    game_data.ecs = ecs;
+
+    script_manager.stop_all();
+    game_data.ecs.resource_mut::<UiData>().message_window = None;
+    game_data.ecs.resource_mut::<PlayerMovementLocked>().0 = false;
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::GameData;
+use crate::script::ScriptManager;
+use std::time::{Duration, Instant};
+
+// Stages run in this order, and systems within a stage run in the order they were added
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Input,
+    Scripts,
+    Movement,
+    Collision,
+    Hierarchy,
+    Camera,
+    Animation,
+    Audio,
+}
+
+// Everything a system might need
+// (Global state is in ecs resources)
+pub struct UpdateContext<'a> {
+    pub game_data: &'a mut GameData,
+    pub script_manager: &'a mut ScriptManager,
+    pub delta: Duration,
+}
+
+pub struct System {
+    pub name: &'static str,
+    pub stage: Stage,
+    pub enabled: bool,
+    // How long the system took this update (zero if disabled)
+    pub duration: Duration,
+    // The ecs change tick when the system last ran, so that Changed queries in the
+    // system match exactly the changes since then
+    last_run_tick: u32,
+    run: fn(&mut UpdateContext),
+}
+
+pub struct Scheduler {
+    // Always sorted by stage
+    systems: Vec<System>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self { systems: Vec::new() }
+    }
+
+    pub fn add_system(&mut self, stage: Stage, name: &'static str, run: fn(&mut UpdateContext)) {
+        if self.systems.iter().any(|s| s.name == name) {
+            log::error!("System added more than once: {name}");
+            return;
+        }
+
+        // After every system of the same or an earlier stage
+        let index = self.systems.partition_point(|s| s.stage <= stage);
+        self.systems.insert(
+            index,
+            System { name, stage, enabled: true, duration: Duration::ZERO, last_run_tick: 0, run },
+        );
+    }
+
+    pub fn run(&mut self, ctx: &mut UpdateContext) {
+        ctx.game_data.ecs.update_events();
+        for system in &mut self.systems {
+            if !system.enabled {
+                system.duration = Duration::ZERO;
+                continue;
+            }
+
+            let start = Instant::now();
+            ctx.game_data.ecs.begin_system(system.last_run_tick);
+            (system.run)(ctx);
+            system.last_run_tick = ctx.game_data.ecs.change_tick();
+            system.duration = start.elapsed();
+        }
+        ctx.game_data.ecs.end_systems();
+    }
+
+    pub fn systems_mut(&mut self) -> impl Iterator<Item = &mut System> {
+        self.systems.iter_mut()
+    }
+}
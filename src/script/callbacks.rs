@@ -1,18 +1,25 @@
 use crate::components::{
-    AnimationComp, Camera, Collision, DualStateAnimationState, DualStateAnims, Facing, NamedAnims,
-    Position, SfxEmitter, SineOffsetAnimation, Sprite, SpriteComp, Walking,
+    AnimationComp, Camera, Collision, Door, DoorState, DualStateAnimationState, DualStateAnims,
+    Facing, NamedAnims, Position, SfxEmitter, SineOffsetAnimation, Sprite, SpriteComp, Tags,
+    Walking,
 };
 use crate::data::{CAMERA_ENTITY_NAME, PLAYER_ENTITY_NAME};
-use crate::ecs::{Ecs, EntityId};
-use crate::math::{Rect, Vec2};
-use crate::misc::{Direction, StoryVars};
-use crate::script::WaitCondition;
-use crate::world::WorldPos;
-use crate::{GameData, MessageWindow, UiData};
-use mlua::{Function, Scope, Table};
-use sdl2::mixer::{Chunk, Music};
+use crate::ecs::{Ecs, EntityId, EntityIdentifier};
+use crate::events::StoryVarChanged;
+use crate::loader::prefabs;
+use crate::math::{MapPos, Rect, Vec2};
+use crate::misc::{
+    Aabb, Direction, Musics, PlayerMovementLocked, Running, SoundEffects, StoryVars,
+};
+use crate::save::{self, QUICKSAVE_PATH};
+use crate::script::{ScriptManager, Subscription, WaitCondition, events};
+use crate::spatial::SpatialIndex;
+use crate::world::{CollisionKind, MapEdit, Tile, World, WorldPos};
+use crate::{GameData, MessageWindow, UiData, pathfinding, update};
+use mlua::{FromLua, Function, Lua, MetaMethod, Scope, Table, UserData, UserDataMethods};
+use sdl2::mixer::Music;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt::{self, Display};
 use std::format as f;
 use std::sync::Arc;
@@ -43,6 +50,42 @@ impl From<Error> for mlua::Error {
     }
 }
 
+// Entity ids are passed to Lua as opaque handles
+// Callbacks that take an entity accept either a handle or a name string
+impl UserData for EntityId {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Eq, |_, id, other: EntityId| Ok(*id == other));
+        methods.add_meta_method(MetaMethod::ToString, |_, id, ()| Ok(f!("{id:?}")));
+    }
+}
+
+impl FromLua for EntityId {
+    fn from_lua(value: mlua::Value, _: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::UserData(ud) => Ok(*ud.borrow::<EntityId>()?),
+            v => Err(mlua::Error::FromLuaConversionError {
+                from: v.type_name(),
+                to: "EntityId".to_string(),
+                message: Some("expected entity handle".to_string()),
+            }),
+        }
+    }
+}
+
+impl FromLua for EntityIdentifier {
+    fn from_lua(value: mlua::Value, lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::String(s) => Ok(EntityIdentifier::Name(s.to_str()?.to_string())),
+            mlua::Value::UserData(_) => Ok(EntityIdentifier::Id(EntityId::from_lua(value, lua)?)),
+            v => Err(mlua::Error::FromLuaConversionError {
+                from: v.type_name(),
+                to: "EntityIdentifier".to_string(),
+                message: Some("expected entity name or entity handle".to_string()),
+            }),
+        }
+    }
+}
+
 // ----------------------------------------------
 // ----------------------------------------------
 
@@ -50,20 +93,80 @@ pub fn bind_general_callbacks<'scope>(
     scope: &'scope Scope<'scope, '_>,
     globals: &Table,
     game_data: &'scope RefCell<&mut GameData>,
-    player_movement_locked: &'scope RefCell<&mut bool>,
-    running: &'scope mut bool,
-    musics: &'scope HashMap<String, Music>,
-    sound_effects: &'scope HashMap<String, Chunk>,
 ) -> mlua::Result<()> {
     globals.set(
         "get_story_var",
-        scope.create_function(|_, args| get_story_var(args, &game_data.borrow().story_vars))?,
+        scope.create_function(|_, args| {
+            get_story_var(args, &game_data.borrow().ecs.resource::<StoryVars>())
+        })?,
     )?;
     globals.set(
         "set_story_var",
-        scope.create_function_mut(|_, args| {
-            set_story_var(args, &mut game_data.borrow_mut().story_vars)
-        })?,
+        scope.create_function_mut(|_, args| set_story_var(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "get_entity_id",
+        scope.create_function(|_, args| get_entity_id(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "spawn_entity",
+        scope.create_function(|_, ()| Ok(game_data.borrow_mut().ecs.add_entity()))?,
+    )?;
+    globals.set(
+        "spawn",
+        scope.create_function(|_, args| spawn(args, &mut game_data.borrow_mut().ecs))?,
+    )?;
+    globals.set(
+        "despawn_entity",
+        scope.create_function(|_, args| despawn_entity(args, &mut game_data.borrow_mut().ecs))?,
+    )?;
+    globals.set(
+        "get_entities_with_tag",
+        scope.create_function(|_, args| get_entities_with_tag(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "set_visible_tag",
+        scope.create_function_mut(|_, args| set_visible_tag(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "set_solid_tag",
+        scope.create_function_mut(|_, args| set_solid_tag(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "despawn_tag",
+        scope.create_function(|_, args| despawn_tag(args, &mut game_data.borrow_mut().ecs))?,
+    )?;
+    globals.set(
+        "get_entities_in_region",
+        scope.create_function(|_, args| get_entities_in_region(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "get_entities_at",
+        scope.create_function(|_, args| get_entities_at(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "get_collision",
+        scope.create_function(|_, args| get_collision(args, &game_data.borrow().world))?,
+    )?;
+    globals.set(
+        "set_collision",
+        scope.create_function(|_, args| set_collision(args, &mut game_data.borrow_mut().world))?,
+    )?;
+    globals.set(
+        "set_tile",
+        scope.create_function(|_, args| set_tile(args, &mut game_data.borrow_mut().world))?,
+    )?;
+    globals.set(
+        "clear_tile",
+        scope.create_function(|_, args| clear_tile(args, &mut game_data.borrow_mut().world))?,
+    )?;
+    globals.set(
+        "get_map_display_name",
+        scope.create_function(|_, args| get_map_display_name(args, &game_data.borrow().world))?,
+    )?;
+    globals.set(
+        "get_nearest_entity",
+        scope.create_function(|_, args| get_nearest_entity(args, &game_data.borrow().ecs))?,
     )?;
     globals.set(
         "get_entity_map_pos",
@@ -101,14 +204,12 @@ pub fn bind_general_callbacks<'scope>(
     )?;
     globals.set(
         "lock_player_input",
-        scope.create_function_mut(|_, args| {
-            lock_player_input(args, *player_movement_locked.borrow_mut(), &game_data.borrow().ecs)
-        })?,
+        scope.create_function_mut(|_, args| lock_player_input(args, &game_data.borrow().ecs))?,
     )?;
     globals.set(
         "unlock_player_input",
         scope.create_function_mut(|_, ()| {
-            **player_movement_locked.borrow_mut() = false;
+            game_data.borrow().ecs.resource_mut::<PlayerMovementLocked>().0 = false;
             Ok(())
         })?,
     )?;
@@ -130,6 +231,10 @@ pub fn bind_general_callbacks<'scope>(
         "walk_to",
         scope.create_function_mut(|_, args| walk_to(args, &game_data.borrow().ecs))?,
     )?;
+    globals.set(
+        "walk_path",
+        scope.create_function_mut(|_, args| walk_path(args, &game_data.borrow()))?,
+    )?;
     globals.set(
         "is_entity_walking",
         scope.create_function(|_, args| is_entity_walking(args, &game_data.borrow().ecs))?,
@@ -144,6 +249,14 @@ pub fn bind_general_callbacks<'scope>(
         scope
             .create_function_mut(|_, args| stop_object_animation(args, &game_data.borrow().ecs))?,
     )?;
+    globals.set(
+        "get_door_state",
+        scope.create_function(|_, args| get_door_state(args, &game_data.borrow().ecs))?,
+    )?;
+    globals.set(
+        "set_door_state",
+        scope.create_function_mut(|_, args| set_door_state(args, &game_data.borrow().ecs))?,
+    )?;
     globals.set(
         "switch_dual_state_animation",
         scope.create_function_mut(|_, args| {
@@ -162,8 +275,18 @@ pub fn bind_general_callbacks<'scope>(
         "anim_jump",
         scope.create_function_mut(|_, args| anim_jump(args, &mut game_data.borrow_mut().ecs))?,
     )?;
-    globals.set("play_sfx", scope.create_function(|_, args| play_sfx(args, sound_effects))?)?;
-    globals.set("play_music", scope.create_function_mut(|_, args| play_music(args, musics))?)?;
+    globals.set(
+        "play_sfx",
+        scope.create_function(|_, args| {
+            play_sfx(args, &game_data.borrow().ecs.resource::<SoundEffects>())
+        })?,
+    )?;
+    globals.set(
+        "play_music",
+        scope.create_function_mut(|_, args| {
+            play_music(args, &game_data.borrow().ecs.resource::<Musics>())
+        })?,
+    )?;
     globals.set("stop_music", scope.create_function_mut(|_, args| stop_music(args))?)?;
     globals.set(
         "emit_entity_sfx",
@@ -176,7 +299,7 @@ pub fn bind_general_callbacks<'scope>(
     globals.set(
         "close_game",
         scope.create_function_mut(|_, ()| {
-            *running = false;
+            game_data.borrow().ecs.resource_mut::<Running>().0 = false;
             Ok(())
         })?,
     )?;
@@ -203,15 +326,20 @@ pub fn bind_general_callbacks<'scope>(
 pub fn bind_script_only_callbacks<'scope>(
     scope: &'scope Scope<'scope, '_>,
     globals: &Table,
-    ui_data: &'scope RefCell<&mut UiData>,
+    game_data: &'scope RefCell<&mut GameData>,
     wait_condition: &'scope RefCell<&mut Option<WaitCondition>>,
+    subscriptions: &'scope RefCell<&mut Vec<Subscription>>,
 ) -> mlua::Result<()> {
     let wrap_yielding: Function = globals.get("wrap_yielding")?;
 
     globals.set(
         "message",
         wrap_yielding.call::<Function>(scope.create_function_mut(|_, args| {
-            message(args, &mut ui_data.borrow_mut().message_window, *wait_condition.borrow_mut())
+            message(
+                args,
+                &mut game_data.borrow().ecs.resource_mut::<UiData>().message_window,
+                *wait_condition.borrow_mut(),
+            )
         })?)?,
     )?;
     globals.set(
@@ -222,6 +350,14 @@ pub fn bind_script_only_callbacks<'scope>(
             Ok(())
         })?)?,
     )?;
+    globals.set(
+        "subscribe",
+        scope.create_function_mut(|_, args| subscribe(args, &mut subscriptions.borrow_mut()))?,
+    )?;
+    globals.set(
+        "unsubscribe",
+        scope.create_function_mut(|_, args| unsubscribe(args, &mut subscriptions.borrow_mut()))?,
+    )?;
 
     Ok(())
 }
@@ -230,18 +366,30 @@ pub fn bind_console_only_callbacks<'scope>(
     scope: &'scope Scope<'scope, '_>,
     globals: &Table,
     game_data: &'scope RefCell<&mut GameData>,
-    ui_data: &'scope RefCell<&mut UiData>,
+    script_manager: &'scope RefCell<&mut ScriptManager>,
 ) -> mlua::Result<()> {
     globals.set(
         "message",
         scope.create_function_mut(|_, args| {
-            message(args, &mut ui_data.borrow_mut().message_window, &mut None)
+            message(
+                args,
+                &mut game_data.borrow().ecs.resource_mut::<UiData>().message_window,
+                &mut None,
+            )
         })?,
     )?;
     globals.set(
         "dump_entities_to_file",
         scope.create_function(|_, args| dump_entities_to_file(args, &game_data.borrow().ecs))?,
     )?;
+    globals
+        .set("save_game", scope.create_function(|_, args| save_game(args, &game_data.borrow()))?)?;
+    globals.set(
+        "load_game",
+        scope.create_function(|_, args| {
+            load_game(args, &mut game_data.borrow_mut(), &mut script_manager.borrow_mut())
+        })?,
+    )?;
 
     Ok(())
 }
@@ -254,42 +402,202 @@ pub fn get_story_var(key: String, story_vars: &StoryVars) -> mlua::Result<i32> {
     story_vars.get(&key).ok_or(Error(f!("no story var `{key}`")).into())
 }
 
-pub fn set_story_var((key, val): (String, i32), story_vars: &mut StoryVars) -> mlua::Result<()> {
-    story_vars.set(&key, val);
+pub fn set_story_var((key, val): (String, i32), ecs: &Ecs) -> mlua::Result<()> {
+    let old_value = ecs.resource::<StoryVars>().get(&key);
+    ecs.resource_mut::<StoryVars>().set(&key, val);
+    if old_value != Some(val) {
+        ecs.send_event(StoryVarChanged { key, old_value, new_value: val });
+    }
+    Ok(())
+}
+
+pub fn get_entity_id(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<EntityId> {
+    ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")).into())
+}
+
+// Spawns an entity from a prefab at a position
+pub fn spawn(
+    (prefab, map, x, y): (String, String, f64, f64),
+    ecs: &mut Ecs,
+) -> mlua::Result<EntityId> {
+    let id = ecs.add_entity();
+    prefabs::add_prefab_components(ecs, id, &prefab, &serde_json::Map::new()).map_err(|e| {
+        ecs.remove_entity(id);
+        Error(f!("couldn't spawn prefab `{prefab}` (err: {e})"))
+    })?;
+    ecs.add_component(id, Position(WorldPos::new(&map, x, y)));
+    Ok(id)
+}
+
+pub fn despawn_entity(entity: EntityIdentifier, ecs: &mut Ecs) -> mlua::Result<()> {
+    let id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
+    ecs.remove_entity(id);
     Ok(())
 }
 
-pub fn get_entity_map_pos(entity: String, ecs: &Ecs) -> mlua::Result<(f64, f64)> {
-    let position = ecs
-        .query_one_with_name::<&Position>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn get_entities_with_tag(tag: String, ecs: &Ecs) -> mlua::Result<Vec<EntityId>> {
+    Ok(ecs
+        .query::<(EntityId, &Tags)>()
+        .filter(|(_, t)| t.contains(&tag))
+        .map(|(id, _)| id)
+        .collect())
+}
+
+// Bulk setters skip tagged entities without the relevant component
+pub fn set_visible_tag((tag, visible): (String, bool), ecs: &Ecs) -> mlua::Result<()> {
+    for (tags, mut sprite) in ecs.query::<(&Tags, &mut SpriteComp)>() {
+        if tags.contains(&tag) {
+            sprite.visible = visible;
+        }
+    }
+    Ok(())
+}
+
+pub fn set_solid_tag((tag, solid): (String, bool), ecs: &Ecs) -> mlua::Result<()> {
+    for (tags, mut collision) in ecs.query::<(&Tags, &mut Collision)>() {
+        if tags.contains(&tag) {
+            collision.solid = solid;
+        }
+    }
+    Ok(())
+}
+
+pub fn despawn_tag(tag: String, ecs: &mut Ecs) -> mlua::Result<()> {
+    for id in get_entities_with_tag(tag, ecs)? {
+        // (Might already be despawned as the child of another tagged entity)
+        if ecs.entity_ids.contains_key(id) {
+            ecs.remove_entity(id);
+        }
+    }
+    Ok(())
+}
+
+// Spatial queries are by the entities' hitboxes, as of the start of the frame
+// (Or their positions, for entities without hitboxes)
+
+// Region by top left corner and size
+pub fn get_entities_in_region(
+    (map, x, y, width, height): (String, f64, f64, f64, f64),
+    ecs: &Ecs,
+) -> mlua::Result<Vec<EntityId>> {
+    let region = Aabb { top: y, bottom: y + height, left: x, right: x + width };
+    Ok(ecs.resource::<SpatialIndex>().query_region(&map, &region))
+}
+
+pub fn get_entities_at((map, x, y): (String, f64, f64), ecs: &Ecs) -> mlua::Result<Vec<EntityId>> {
+    Ok(ecs.resource::<SpatialIndex>().query_point(&map, Vec2::new(x, y)))
+}
+
+// Name of the collision kind at a map position ("solid", "ledge_down", "trigger", etc.)
+pub fn get_collision(
+    (map, x, y): (String, f64, f64),
+    world: &World,
+) -> mlua::Result<Option<&'static str>> {
+    let map = world.maps.get(&map).ok_or(Error(f!("invalid map `{map}`")))?;
+    Ok(map.collision_kind_at(Vec2::new(x, y)).map(|kind| kind.name()))
+}
+
+// Map edits are kept in save data
+// A nil kind removes the collision
+pub fn set_collision(
+    (map_name, x, y, kind): (String, f64, f64, Option<String>),
+    world: &mut World,
+) -> mlua::Result<()> {
+    let map = world.maps.get(&map_name).ok_or(Error(f!("invalid map `{map_name}`")))?;
+    let kind = kind
+        .map(|k| CollisionKind::from_identifier(&k).ok_or(Error(f!("invalid collision `{k}`"))))
+        .transpose()?;
+    let (x, y) = map.collision_coords(Vec2::new(x, y));
+
+    world
+        .edit_map(MapEdit::Collision { map: map_name, x, y, kind })
+        .map_err(|e| Error(e.to_string()))?;
+    Ok(())
+}
+
+// The tile of a layer at a map position
+pub fn set_tile(
+    (map_name, layer_name, x, y, tile_id): (String, String, f64, f64, u32),
+    world: &mut World,
+) -> mlua::Result<()> {
+    edit_tile((map_name, layer_name, x, y), Some(Tile::new(tile_id)), world)
+}
+
+pub fn clear_tile(
+    (map_name, layer_name, x, y): (String, String, f64, f64),
+    world: &mut World,
+) -> mlua::Result<()> {
+    edit_tile((map_name, layer_name, x, y), None, world)
+}
+
+fn edit_tile(
+    (map_name, layer_name, x, y): (String, String, f64, f64),
+    tile: Option<Tile>,
+    world: &mut World,
+) -> mlua::Result<()> {
+    let map = world.maps.get(&map_name).ok_or(Error(f!("invalid map `{map_name}`")))?;
+    let layer = map
+        .tile_layers
+        .iter()
+        .find(|l| l.name == layer_name)
+        .ok_or(Error(f!("invalid tile layer `{layer_name}`")))?;
+    let (x, y) = map.tile_coords(layer, Vec2::new(x, y), world.cell_size);
+
+    world
+        .edit_map(MapEdit::Tile { map: map_name, layer: layer_name, x, y, tile })
+        .map_err(|e| Error(e.to_string()))?;
+    Ok(())
+}
+
+// The display_name field of the map's LDtk level, if it has one
+pub fn get_map_display_name(map: String, world: &World) -> mlua::Result<Option<String>> {
+    let map = world.maps.get(&map).ok_or(Error(f!("invalid map `{map}`")))?;
+    Ok(map.properties.display_name.clone())
+}
+
+// Optionally excluding an entity (such as the one searching)
+pub fn get_nearest_entity(
+    (map, x, y, max_distance, except): (String, f64, f64, f64, Option<EntityIdentifier>),
+    ecs: &Ecs,
+) -> mlua::Result<Option<EntityId>> {
+    let except = except.and_then(|e| ecs.find_entity(e));
+    Ok(ecs
+        .resource::<SpatialIndex>()
+        .nearest(&map, Vec2::new(x, y), max_distance, |id| Some(id) != except))
+}
+
+pub fn get_entity_map_pos(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<(f64, f64)> {
+    let position =
+        ecs.query_one::<&Position>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     Ok((position.map_pos.x, position.map_pos.y))
 }
 
 // Requires entity to have a position component already, since map is omitted
-pub fn set_entity_map_pos((entity, x, y): (String, f64, f64), ecs: &Ecs) -> mlua::Result<()> {
-    let mut position = ecs
-        .query_one_with_name::<&mut Position>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn set_entity_map_pos(
+    (entity, x, y): (EntityIdentifier, f64, f64),
+    ecs: &Ecs,
+) -> mlua::Result<()> {
+    let mut position =
+        ecs.query_one::<&mut Position>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     position.map_pos = Vec2::new(x, y);
     Ok(())
 }
 
-pub fn get_entity_world_pos(entity: String, ecs: &Ecs) -> mlua::Result<(String, f64, f64)> {
-    let position = ecs
-        .query_one_with_name::<&Position>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn get_entity_world_pos(
+    entity: EntityIdentifier,
+    ecs: &Ecs,
+) -> mlua::Result<(String, f64, f64)> {
+    let position =
+        ecs.query_one::<&Position>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     Ok((position.map.clone(), position.map_pos.x, position.map_pos.y))
 }
 
 // Will attach a new position component
 pub fn set_entity_world_pos(
-    (entity, map, x, y): (String, String, f64, f64),
+    (entity, map, x, y): (EntityIdentifier, String, f64, f64),
     ecs: &mut Ecs,
 ) -> mlua::Result<()> {
-    let entity_id = ecs
-        .query_one_with_name::<EntityId>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+    let entity_id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     ecs.add_component(entity_id, Position(WorldPos::new(&map, x, y)));
     Ok(())
 }
@@ -297,11 +605,11 @@ pub fn set_entity_world_pos(
 #[rustfmt::skip]
 pub fn set_forced_sprite(
     (entity, spritesheet, rect_x, rect_y, rect_w, rect_h, anchor_x, anchor_y):
-        (String, String, u32, u32, u32, u32, i32, i32,),
+        (EntityIdentifier, String, u32, u32, u32, u32, i32, i32,),
     ecs: &Ecs,
 ) -> mlua::Result<()> {
     let mut sprite_component = ecs
-        .query_one_with_name::<&mut SpriteComp>(&entity)
+        .query_one::<&mut SpriteComp>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     sprite_component.forced_sprite = Some(Sprite {
@@ -313,48 +621,47 @@ pub fn set_forced_sprite(
     Ok(())
 }
 
-pub fn remove_forced_sprite(entity: String, ecs: &Ecs) -> mlua::Result<()> {
-    let mut sprite_component = ecs
-        .query_one_with_name::<&mut SpriteComp>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn remove_forced_sprite(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<()> {
+    let mut sprite_component =
+        ecs.query_one::<&mut SpriteComp>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     sprite_component.forced_sprite = None;
     Ok(())
 }
 
-pub fn set_entity_visible((entity, visible): (String, bool), ecs: &Ecs) -> mlua::Result<()> {
-    let mut sprite = ecs
-        .query_one_with_name::<&mut SpriteComp>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn set_entity_visible(
+    (entity, visible): (EntityIdentifier, bool),
+    ecs: &Ecs,
+) -> mlua::Result<()> {
+    let mut sprite =
+        ecs.query_one::<&mut SpriteComp>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     sprite.visible = visible;
     Ok(())
 }
 
-pub fn set_entity_solid((entity, enabled): (String, bool), ecs: &Ecs) -> mlua::Result<()> {
-    let mut collision = ecs
-        .query_one_with_name::<&mut Collision>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn set_entity_solid(
+    (entity, enabled): (EntityIdentifier, bool),
+    ecs: &Ecs,
+) -> mlua::Result<()> {
+    let mut collision =
+        ecs.query_one::<&mut Collision>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     collision.solid = enabled;
     Ok(())
 }
 
-pub fn lock_player_input(
-    _args: (),
-    player_movement_locked: &mut bool,
-    ecs: &Ecs,
-) -> mlua::Result<()> {
-    *player_movement_locked = true;
+pub fn lock_player_input(_args: (), ecs: &Ecs) -> mlua::Result<()> {
+    ecs.resource_mut::<PlayerMovementLocked>().0 = true;
 
     // I'll get to this in the movement rework
-    if let Some(mut walking) = ecs.query_one_with_name::<&mut Walking>(PLAYER_ENTITY_NAME) {
+    if let Some(mut walking) = ecs.query_one::<&mut Walking>(PLAYER_ENTITY_NAME) {
         walking.speed = 0.;
     }
 
     Ok(())
 }
 
-pub fn set_camera_target(entity: String, ecs: &Ecs) -> mlua::Result<()> {
+pub fn set_camera_target(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<()> {
     let mut camera_component = ecs
-        .query_one_with_name::<&mut Camera>(CAMERA_ENTITY_NAME)
+        .query_one::<&mut Camera>(CAMERA_ENTITY_NAME)
         .ok_or(Error("no camera entity".to_string()))?;
     camera_component.target_entity = Some(entity);
     Ok(())
@@ -362,7 +669,7 @@ pub fn set_camera_target(entity: String, ecs: &Ecs) -> mlua::Result<()> {
 
 pub fn remove_camera_target(ecs: &Ecs) -> mlua::Result<()> {
     let mut camera_component = ecs
-        .query_one_with_name::<&mut Camera>(CAMERA_ENTITY_NAME)
+        .query_one::<&mut Camera>(CAMERA_ENTITY_NAME)
         .ok_or(Error("no camera entity".to_string()))?;
     camera_component.target_entity = None;
     Ok(())
@@ -377,11 +684,11 @@ pub fn set_camera_clamp(clamp: bool, ecs: &Ecs) -> mlua::Result<()> {
 
 // I'll get to this in the movement rework
 pub fn walk(
-    (entity, direction, distance, speed): (String, String, f64, f64),
+    (entity, direction, distance, speed): (EntityIdentifier, String, f64, f64),
     ecs: &Ecs,
 ) -> mlua::Result<()> {
     let (position, mut walking, facing) = ecs
-        .query_one_with_name::<(&Position, &mut Walking, Option<&mut Facing>)>(&entity)
+        .query_one::<(&Position, &mut Walking, Option<&mut Facing>)>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     walking.direction = match direction.as_str() {
@@ -393,6 +700,7 @@ pub fn walk(
     }?;
 
     walking.speed = speed;
+    walking.waypoints.clear();
 
     walking.destination = Some(
         position.map_pos
@@ -413,11 +721,11 @@ pub fn walk(
 
 // I'll get to this in the movement rework
 pub fn walk_to(
-    (entity, direction, destination, speed): (String, String, f64, f64),
+    (entity, direction, destination, speed): (EntityIdentifier, String, f64, f64),
     ecs: &Ecs,
 ) -> mlua::Result<()> {
     let (position, mut walking, facing) = ecs
-        .query_one_with_name::<(&Position, &mut Walking, Option<&mut Facing>)>(&entity)
+        .query_one::<(&Position, &mut Walking, Option<&mut Facing>)>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     walking.direction = match direction.as_str() {
@@ -429,6 +737,7 @@ pub fn walk_to(
     }?;
 
     walking.speed = speed;
+    walking.waypoints.clear();
 
     walking.destination = Some(match walking.direction {
         Direction::Up | Direction::Down => Vec2::new(position.map_pos.x, destination),
@@ -442,33 +751,113 @@ pub fn walk_to(
     Ok(())
 }
 
-pub fn is_entity_walking(entity: String, ecs: &Ecs) -> mlua::Result<bool> {
-    let walking = ecs
-        .query_one_with_name::<&Walking>(&entity)
+// Walks around collisions and solid entities (see pathfinding::find_path)
+pub fn walk_path(
+    (entity, x, y, speed): (EntityIdentifier, f64, f64, f64),
+    game_data: &GameData,
+) -> mlua::Result<()> {
+    let GameData { world, ecs, .. } = game_data;
+    let entity_id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
+    let (position, collision) = ecs
+        .query_one_with_id::<(&Position, Option<&Collision>)>(entity_id)
+        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+    let map = world.maps.get(&position.map).ok_or(Error(f!("invalid map `{}`", position.map)))?;
+
+    let hitbox = collision.map(|c| c.hitbox).unwrap_or_default();
+    let mut waypoints: VecDeque<MapPos> =
+        pathfinding::find_path(ecs, map, entity_id, hitbox, position.map_pos, Vec2::new(x, y))
+            .ok_or(Error(f!("no path for `{entity}` to ({x}, {y})")))?
+            .into();
+    let start = position.map_pos;
+    drop(position);
+
+    let (mut walking, facing) = ecs
+        .query_one_with_id::<(&mut Walking, Option<&mut Facing>)>(entity_id)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
+
+    walking.speed = speed;
+    // (Already there, it finishes walking right away)
+    let first = waypoints.pop_front().unwrap_or(start);
+    if first != start {
+        walking.direction = Direction::towards(start, first);
+    }
+    walking.destination = Some(first);
+    walking.waypoints = waypoints;
+
+    if let Some(mut facing) = facing {
+        facing.0 = walking.direction;
+    }
+
+    Ok(())
+}
+
+pub fn is_entity_walking(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<bool> {
+    let walking =
+        ecs.query_one::<&Walking>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     Ok(walking.destination.is_some())
 }
 
-pub fn play_object_animation((entity, repeat): (String, bool), ecs: &Ecs) -> mlua::Result<()> {
+pub fn play_object_animation(
+    (entity, repeat): (EntityIdentifier, bool),
+    ecs: &Ecs,
+) -> mlua::Result<()> {
     let mut anim_comp = ecs
-        .query_one_with_name::<&mut AnimationComp>(&entity)
+        .query_one::<&mut AnimationComp>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     anim_comp.start(repeat);
     Ok(())
 }
 
-pub fn stop_object_animation(entity: String, ecs: &Ecs) -> mlua::Result<()> {
+pub fn stop_object_animation(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<()> {
     let mut anim_comp = ecs
-        .query_one_with_name::<&mut AnimationComp>(&entity)
+        .query_one::<&mut AnimationComp>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
     anim_comp.stop();
     Ok(())
 }
 
-pub fn switch_dual_state_animation((entity, state): (String, i32), ecs: &Ecs) -> mlua::Result<()> {
+// States are "open", "closed" or "locked"
+pub fn get_door_state(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<&'static str> {
+    let door = ecs.query_one::<&Door>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
+
+    Ok(match door.state {
+        DoorState::Open => "open",
+        DoorState::Closed => "closed",
+        DoorState::Locked => "locked",
+    })
+}
+
+// (Setting a locked door open doesn't need its key)
+// An open door can't be closed or locked while something solid is in its way
+pub fn set_door_state((entity, state): (EntityIdentifier, String), ecs: &Ecs) -> mlua::Result<()> {
+    let (door_id, mut door) = ecs
+        .query_one::<(EntityId, &mut Door)>(&entity)
+        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+
+    let new_state = match state.as_str() {
+        "open" => Ok(DoorState::Open),
+        "closed" => Ok(DoorState::Closed),
+        "locked" => Ok(DoorState::Locked),
+        s => Err(Error(f!("invalid door state `{s}`"))),
+    }?;
+    if door.state == DoorState::Open
+        && new_state != DoorState::Open
+        && update::door_blocked(ecs, door_id)
+    {
+        return Err(Error(f!("door `{entity}` is blocked")).into());
+    }
+    door.state = new_state;
+
+    Ok(())
+}
+
+pub fn switch_dual_state_animation(
+    (entity, state): (EntityIdentifier, i32),
+    ecs: &Ecs,
+) -> mlua::Result<()> {
     let (mut anim_comp, mut dual_anims) = ecs
-        .query_one_with_name::<(&mut AnimationComp, &mut DualStateAnims)>(&entity)
+        .query_one::<(&mut AnimationComp, &mut DualStateAnims)>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     let state = match state {
@@ -484,11 +873,11 @@ pub fn switch_dual_state_animation((entity, state): (String, i32), ecs: &Ecs) ->
 }
 
 pub fn play_named_animation(
-    (entity, animation, repeat): (String, String, bool),
+    (entity, animation, repeat): (EntityIdentifier, String, bool),
     ecs: &Ecs,
 ) -> mlua::Result<()> {
     let (mut anim_comp, anims) = ecs
-        .query_one_with_name::<(&mut AnimationComp, &NamedAnims)>(&entity)
+        .query_one::<(&mut AnimationComp, &NamedAnims)>(&entity)
         .ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     let clip = anims
@@ -502,10 +891,8 @@ pub fn play_named_animation(
     Ok(())
 }
 
-pub fn anim_quiver((entity, duration): (String, f64), ecs: &mut Ecs) -> mlua::Result<()> {
-    let id = ecs
-        .query_one_with_name::<EntityId>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn anim_quiver((entity, duration): (EntityIdentifier, f64), ecs: &mut Ecs) -> mlua::Result<()> {
+    let id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     ecs.add_component(
         id,
@@ -521,10 +908,8 @@ pub fn anim_quiver((entity, duration): (String, f64), ecs: &mut Ecs) -> mlua::Re
     Ok(())
 }
 
-pub fn anim_jump(entity: String, ecs: &mut Ecs) -> mlua::Result<()> {
-    let id = ecs
-        .query_one_with_name::<EntityId>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn anim_jump(entity: EntityIdentifier, ecs: &mut Ecs) -> mlua::Result<()> {
+    let id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     ecs.add_component(
         id,
@@ -540,16 +925,13 @@ pub fn anim_jump(entity: String, ecs: &mut Ecs) -> mlua::Result<()> {
     Ok(())
 }
 
-pub fn play_sfx(name: String, sound_effects: &HashMap<String, Chunk>) -> mlua::Result<()> {
+pub fn play_sfx(name: String, sound_effects: &SoundEffects) -> mlua::Result<()> {
     let sfx = sound_effects.get(&name).ok_or(Error(f!("no sfx `{name}`")))?;
     sdl2::mixer::Channel::all().play(sfx, 0).map_err(|e| Error(e))?;
     Ok(())
 }
 
-pub fn play_music(
-    (name, should_loop): (String, bool),
-    musics: &HashMap<String, Music>,
-) -> mlua::Result<()> {
+pub fn play_music((name, should_loop): (String, bool), musics: &Musics) -> mlua::Result<()> {
     let music = musics.get(&name).ok_or(Error(f!("no music `{name}`")))?;
     music.play(if should_loop { -1 } else { 0 }).map_err(|e| Error(e))?;
     Ok(())
@@ -561,33 +943,29 @@ pub fn stop_music(fade_out_time: f64) -> mlua::Result<()> {
 }
 
 pub fn emit_entity_sfx(
-    (entity, sfx, repeat): (String, String, bool),
+    (entity, sfx, repeat): (EntityIdentifier, String, bool),
     ecs: &Ecs,
 ) -> mlua::Result<()> {
-    let mut sfx_comp = ecs
-        .query_one_with_name::<&mut SfxEmitter>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+    let mut sfx_comp =
+        ecs.query_one::<&mut SfxEmitter>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     sfx_comp.sfx_name = Some(sfx);
     sfx_comp.repeat = repeat;
     Ok(())
 }
 
-pub fn stop_entity_sfx(entity: String, ecs: &Ecs) -> mlua::Result<()> {
-    let mut sfx_comp = ecs
-        .query_one_with_name::<&mut SfxEmitter>(&entity)
-        .ok_or(Error(f!("invalid entity `{entity}`")))?;
+pub fn stop_entity_sfx(entity: EntityIdentifier, ecs: &Ecs) -> mlua::Result<()> {
+    let mut sfx_comp =
+        ecs.query_one::<&mut SfxEmitter>(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
     sfx_comp.sfx_name = None;
     sfx_comp.repeat = false;
     Ok(())
 }
 
 pub fn add_component(
-    (entity_name, component_name, component_json): (String, String, String),
+    (entity, component_name, component_json): (EntityIdentifier, String, String),
     ecs: &mut Ecs,
 ) -> mlua::Result<()> {
-    let entity_id = ecs
-        .query_one_with_name::<EntityId>(&entity_name)
-        .ok_or(Error(f!("invalid entity `{entity_name}`")))?;
+    let entity_id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     let value = serde_json::from_str::<serde_json::Value>(&component_json)
         .map_err(|e| Error(f!("invalid json (err: {e})")))?;
@@ -599,12 +977,10 @@ pub fn add_component(
 }
 
 pub fn remove_component(
-    (entity_name, component_name): (String, String),
+    (entity, component_name): (EntityIdentifier, String),
     ecs: &mut Ecs,
 ) -> mlua::Result<()> {
-    let id = ecs
-        .query_one_with_name::<EntityId>(&entity_name)
-        .ok_or(Error(f!("invalid entity `{entity_name}`")))?;
+    let id = ecs.find_entity(&entity).ok_or(Error(f!("invalid entity `{entity}`")))?;
 
     ecs.remove_component_with_name(id, &component_name).map_err(|e| Error(e.to_string()))?;
 
@@ -623,6 +999,22 @@ pub fn dump_entities_to_file(path: String, ecs: &Ecs) -> mlua::Result<()> {
     Ok(())
 }
 
+pub fn save_game(path: Option<String>, game_data: &GameData) -> mlua::Result<()> {
+    let path = path.unwrap_or(QUICKSAVE_PATH.to_string());
+    save::save_game(game_data, &path).map_err(|e| Error(e.to_string()))?;
+    Ok(())
+}
+
+pub fn load_game(
+    path: Option<String>,
+    game_data: &mut GameData,
+    script_manager: &mut ScriptManager,
+) -> mlua::Result<()> {
+    let path = path.unwrap_or(QUICKSAVE_PATH.to_string());
+    save::load_game(game_data, script_manager, &path).map_err(|e| Error(e.to_string()))?;
+    Ok(())
+}
+
 pub fn message(
     message: String,
     message_window: &mut Option<MessageWindow>,
@@ -632,3 +1024,20 @@ pub fn message(
     *wait_condition = Some(WaitCondition::Message);
     Ok(())
 }
+
+pub fn subscribe(
+    (event_name, handler): (String, Function),
+    subscriptions: &mut Vec<Subscription>,
+) -> mlua::Result<()> {
+    if !events::lua_events().iter().any(|(name, _)| *name == event_name) {
+        return Err(Error(f!("invalid event `{event_name}`")).into());
+    }
+    subscriptions.push(Subscription { event_name, handler });
+    Ok(())
+}
+
+// Removes every handler of the event
+pub fn unsubscribe(event_name: String, subscriptions: &mut Vec<Subscription>) -> mlua::Result<()> {
+    subscriptions.retain(|s| s.event_name != event_name);
+    Ok(())
+}
@@ -1,9 +1,7 @@
-use crate::script::callbacks;
-use crate::{GameData, UiData};
+use crate::GameData;
+use crate::script::{ScriptManager, callbacks};
 use mlua::{FromLuaMulti, Lua};
-use sdl2::mixer::{Chunk, Music};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::format as f;
 
 pub struct ConsoleCommandExecutor {
@@ -13,31 +11,22 @@ pub struct ConsoleCommandExecutor {
 }
 
 impl ConsoleCommandExecutor {
-    pub fn execute(
-        &mut self,
-        game_data: &mut GameData,
-        ui_data: &mut UiData,
-        player_movement_locked: &mut bool,
-        running: &mut bool,
-        musics: &HashMap<String, Music>,
-        sound_effects: &HashMap<String, Chunk>,
-    ) {
+    pub fn execute(&mut self, game_data: &mut GameData, script_manager: &mut ScriptManager) {
         let r: mlua::Result<()> = try {
             let game_data = RefCell::new(game_data);
-            let ui_data = RefCell::new(ui_data);
-            let player_movement_locked = RefCell::new(player_movement_locked);
+            let script_manager = RefCell::new(script_manager);
 
             self.lua_instance.scope(|scope| -> mlua::Result<()> {
                 let globals = self.lua_instance.globals();
 
-                #[rustfmt::skip]
-                callbacks::bind_general_callbacks(
-                    scope, &globals, &game_data, &player_movement_locked, running,
-                    musics, sound_effects,
+                callbacks::bind_general_callbacks(scope, &globals, &game_data)?;
+                callbacks::bind_console_only_callbacks(
+                    scope,
+                    &globals,
+                    &game_data,
+                    &script_manager,
                 )?;
 
-                callbacks::bind_console_only_callbacks(scope, &globals, &game_data, &ui_data)?;
-
                 for input in self.input_queue.drain(..) {
                     let r: ReturnValuesString = self.lua_instance.load(&input).eval()?;
                     if !r.0.is_empty() {
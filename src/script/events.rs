@@ -0,0 +1,93 @@
+use crate::ecs::{Ecs, Event};
+use crate::events::{
+    AnimationFinished, EnteredArea, ExitedArea, Interacted, MapChanged, StoryVarChanged,
+    WalkFinished,
+};
+use mlua::{IntoLua, Lua};
+use std::ops::Range;
+
+// Reads the events between two event cursors (see Ecs::event_cursor)
+type ReadEvents = fn(&Ecs, &Lua, Range<u64>) -> mlua::Result<Vec<mlua::Value>>;
+
+// Every event that scripts can subscribe to, by name
+pub fn lua_events() -> [(&'static str, ReadEvents); 7] {
+    [
+        (AnimationFinished::name(), read_events::<AnimationFinished>),
+        (WalkFinished::name(), read_events::<WalkFinished>),
+        (EnteredArea::name(), read_events::<EnteredArea>),
+        (ExitedArea::name(), read_events::<ExitedArea>),
+        (Interacted::name(), read_events::<Interacted>),
+        (StoryVarChanged::name(), read_events::<StoryVarChanged>),
+        (MapChanged::name(), read_events::<MapChanged>),
+    ]
+}
+
+fn read_events<E>(ecs: &Ecs, lua: &Lua, cursors: Range<u64>) -> mlua::Result<Vec<mlua::Value>>
+where
+    E: Event + IntoLua,
+{
+    ecs.read_events_between::<E>(cursors).into_iter().map(|e| e.into_lua(lua)).collect()
+}
+
+// Events are passed to Lua as tables with the same fields
+
+impl IntoLua for AnimationFinished {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("entity", self.entity)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for WalkFinished {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("entity", self.entity)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for EnteredArea {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("entity", self.entity)?;
+        table.set("area", self.area)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for ExitedArea {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("entity", self.entity)?;
+        table.set("area", self.area)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for Interacted {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("entity", self.entity)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for StoryVarChanged {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("key", self.key)?;
+        table.set("old_value", self.old_value)?;
+        table.set("new_value", self.new_value)?;
+        table.into_lua(lua)
+    }
+}
+
+impl IntoLua for MapChanged {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("old_map", self.old_map)?;
+        table.set("new_map", self.new_map)?;
+        table.into_lua(lua)
+    }
+}
@@ -1,5 +1,6 @@
 pub mod callbacks;
 pub mod console;
+pub mod events;
 
 mod script;
 pub use script::*;
@@ -16,6 +16,11 @@ function walk_to_wait(entity, direction, destination, speed)
   wait_until_not_walking(entity)
 end
 
+function walk_path_wait(entity, x, y, speed)
+  walk_path(entity, x, y, speed)
+  wait_until_not_walking(entity)
+end
+
 function wait_until_not_walking(entity)
   while (is_entity_walking(entity)) do
     line_yielded_at = current_line(3)
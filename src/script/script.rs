@@ -1,13 +1,13 @@
+use crate::ecs::Ecs;
 use crate::misc::{self, StoryVars};
-use crate::script::callbacks;
+use crate::script::{callbacks, events};
 use crate::{GameData, UiData};
 use anyhow::{Context, anyhow};
-use mlua::{Lua, Thread, ThreadStatus};
+use mlua::{Function, Lua, Thread, ThreadStatus};
 use regex::Regex;
-use sdl2::mixer::{Chunk, Music};
 use slotmap::{SlotMap, new_key_type};
 use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::format as f;
 use std::path::Path;
 use std::sync::LazyLock;
@@ -28,6 +28,16 @@ pub struct ScriptInstance {
     pub source: String,
     pub name: Option<String>,
     pub wait_condition: Option<WaitCondition>,
+    // A script with subscriptions stays alive after it finishes, to keep handling events
+    pub subscriptions: Vec<Subscription>,
+    // Every script reads the events sent since its own last update, since they all run in the same
+    // system (so each one sees the events sent by the others and by itself)
+    pub event_cursor: u64,
+}
+
+pub struct Subscription {
+    pub event_name: String,
+    pub handler: Function,
 }
 
 #[derive(Clone)]
@@ -47,37 +57,35 @@ impl ScriptManager {
         self.start_queue.push_back(source.to_string());
     }
 
-    pub fn update(
-        &mut self,
-        game_data: &mut GameData,
-        ui_data: &mut UiData,
-        player_movement_locked: &mut bool,
-        running: &mut bool,
-        musics: &HashMap<String, Music>,
-        sound_effects: &HashMap<String, Chunk>,
-    ) {
+    // Running scripts can't be saved, so they're stopped when a game is loaded
+    pub fn stop_all(&mut self) {
+        self.instances.clear();
+        self.start_queue.clear();
+    }
+
+    pub fn update(&mut self, game_data: &mut GameData) {
         for source in std::mem::take(&mut self.start_queue) {
-            self.start_script(&source, &game_data.story_vars);
+            self.start_script(&source, &game_data.ecs);
         }
 
         for instance in self.instances.values_mut() {
-            #[rustfmt::skip]
-            instance.update(
-                game_data, ui_data, player_movement_locked, running, musics,
-                sound_effects,
-            );
+            instance.update(game_data);
         }
 
-        self.instances.retain(|_, instance| instance.thread.status() == ThreadStatus::Resumable);
+        self.instances.retain(|_, instance| {
+            instance.thread.status() == ThreadStatus::Resumable
+                || !instance.subscriptions.is_empty()
+        });
     }
 
-    fn start_script(&mut self, source: &str, story_vars: &StoryVars) {
+    fn start_script(&mut self, source: &str, ecs: &Ecs) {
         let metadata = extract_metadata(source);
+        let story_vars = ecs.resource::<StoryVars>();
 
         let r: mlua::Result<()> = try {
             // Skip if start condition exists and is false or invalid
             if let Some(condition) = &metadata.start_condition
-                && evaluate_story_var_condition(condition, story_vars)
+                && evaluate_story_var_condition(condition, &story_vars)
                     .tap_err(|e| {
                         log::error!(once = true; "Invalid story var condition `{condition}` (err: {e})")
                     })
@@ -121,6 +129,8 @@ impl ScriptManager {
                 source: source.to_string(),
                 name: metadata.name,
                 wait_condition: None,
+                subscriptions: Vec::new(),
+                event_cursor: ecs.event_cursor(),
             });
         };
         r.unwrap_or_else(|e| log::error!("Couldn't start script (err: {e})"));
@@ -128,47 +138,72 @@ impl ScriptManager {
 }
 
 impl ScriptInstance {
-    pub fn update(
-        &mut self,
-        game_data: &mut GameData,
-        ui_data: &mut UiData,
-        player_movement_locked: &mut bool,
-        running: &mut bool,
-        musics: &HashMap<String, Music>,
-        sound_effects: &HashMap<String, Chunk>,
-    ) {
-        // Update wait condition and skip if still waiting
+    pub fn update(&mut self, game_data: &mut GameData) {
+        // Update wait condition and skip if still waiting (and there are no events to handle)
+        let message_window_open = game_data.ecs.resource::<UiData>().message_window.is_some();
         self.wait_condition = match self.wait_condition.clone() {
             Some(WaitCondition::Time(until)) if until < Instant::now() => None,
-            Some(WaitCondition::Message) if ui_data.message_window.is_none() => None,
+            Some(WaitCondition::Message) if !message_window_open => None,
             x => x,
         };
-        if self.wait_condition.is_some() {
+        let resume =
+            self.wait_condition.is_none() && self.thread.status() == ThreadStatus::Resumable;
+
+        // Events sent from here on (including by this script) are read next update
+        let event_cursors = self.event_cursor..game_data.ecs.event_cursor();
+        self.event_cursor = event_cursors.end;
+
+        if !resume && self.subscriptions.is_empty() {
             return;
         }
 
         // Pack mut refs into RefCells for passing into callbacks
         let game_data = RefCell::new(game_data);
-        let ui_data = RefCell::new(ui_data);
-        let player_movement_locked = RefCell::new(player_movement_locked);
         let wait_condition = RefCell::new(&mut self.wait_condition);
+        let subscriptions = RefCell::new(&mut self.subscriptions);
 
         self.lua_instance
             .scope(|scope| {
                 let globals = self.lua_instance.globals();
 
-                #[rustfmt::skip]
-                callbacks::bind_general_callbacks(
-                    scope, &globals, &game_data, &player_movement_locked, running,
-                    musics, sound_effects,
-                )?;
-
-                #[rustfmt::skip]
+                callbacks::bind_general_callbacks(scope, &globals, &game_data)?;
                 callbacks::bind_script_only_callbacks(
-                    scope, &globals, &ui_data, &wait_condition,
+                    scope,
+                    &globals,
+                    &game_data,
+                    &wait_condition,
+                    &subscriptions,
                 )?;
 
-                self.thread.resume::<()>(())?;
+                // Handlers run outside of the script's thread, so they can't yield (can't call
+                // message, wait, etc)
+                // (Collected first, since handlers can subscribe and unsubscribe)
+                for (event_name, read_events) in events::lua_events() {
+                    let handlers: Vec<Function> = subscriptions
+                        .borrow()
+                        .iter()
+                        .filter(|s| s.event_name == event_name)
+                        .map(|s| s.handler.clone())
+                        .collect();
+                    if handlers.is_empty() {
+                        continue;
+                    }
+
+                    let events = read_events(
+                        &game_data.borrow().ecs,
+                        &self.lua_instance,
+                        event_cursors.clone(),
+                    )?;
+                    for event in events {
+                        for handler in &handlers {
+                            handler.call::<()>(event.clone())?;
+                        }
+                    }
+                }
+
+                if resume {
+                    self.thread.resume::<()>(())?;
+                }
 
                 Ok(())
             })
@@ -223,7 +258,10 @@ pub fn extract_metadata(source: &str) -> ScriptMetadata {
     metadata
 }
 
-fn evaluate_story_var_condition(expression: &str, story_vars: &StoryVars) -> anyhow::Result<bool> {
+pub fn evaluate_story_var_condition(
+    expression: &str,
+    story_vars: &StoryVars,
+) -> anyhow::Result<bool> {
     // Compile regex only once ever
     static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([^{}]*)\}").expect("is valid"));
 
@@ -236,3 +274,80 @@ fn evaluate_story_var_condition(expression: &str, story_vars: &StoryVars) -> any
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ScriptManager;
+    use crate::ecs::Ecs;
+    use crate::misc::StoryVars;
+    use crate::world::World;
+    use crate::{GameData, UiData};
+    use std::collections::HashMap;
+
+    fn game_data(story_vars: &[&str]) -> GameData {
+        let mut ecs = Ecs::new();
+        ecs.insert_resource(StoryVars(story_vars.iter().map(|k| (k.to_string(), 0)).collect()));
+        ecs.insert_resource(UiData { message_window: None });
+        GameData { world: World::new(16), ecs, auto_scripts: Vec::new() }
+    }
+
+    fn story_vars(game_data: &GameData) -> HashMap<String, i32> {
+        game_data.ecs.resource::<StoryVars>().0.clone()
+    }
+
+    #[test]
+    fn handlers_receive_events_from_any_script() {
+        let mut game_data = game_data(&["key", "received_by_other", "received_by_sender"]);
+        let mut script_manager = ScriptManager::new();
+
+        // Updated before the sender
+        script_manager.queue_script(
+            r#"
+            subscribe("StoryVarChanged", function(e)
+                if e.key == "key" then set_story_var("received_by_other", e.new_value) end
+            end)
+            "#,
+        );
+        // Receives its own event
+        script_manager.queue_script(
+            r#"
+            subscribe("StoryVarChanged", function(e)
+                if e.key == "key" then set_story_var("received_by_sender", e.new_value) end
+            end)
+            set_story_var("key", 5)
+            "#,
+        );
+
+        script_manager.update(&mut game_data);
+        assert_eq!(story_vars(&game_data)["received_by_other"], 0);
+
+        script_manager.update(&mut game_data);
+        assert_eq!(story_vars(&game_data)["received_by_other"], 5);
+        assert_eq!(story_vars(&game_data)["received_by_sender"], 5);
+
+        // Each event is handled once
+        game_data.ecs.resource_mut::<StoryVars>().0.insert("received_by_other".to_string(), 0);
+        script_manager.update(&mut game_data);
+        assert_eq!(story_vars(&game_data)["received_by_other"], 0);
+    }
+
+    #[test]
+    fn first_assignment_of_a_story_var_is_a_change() {
+        let mut game_data = game_data(&["old_value_was_nil"]);
+        let mut script_manager = ScriptManager::new();
+        script_manager.queue_script(
+            r#"
+            subscribe("StoryVarChanged", function(e)
+                if e.key == "new_key" and e.old_value == nil then
+                    set_story_var("old_value_was_nil", e.new_value)
+                end
+            end)
+            set_story_var("new_key", 3)
+            "#,
+        );
+
+        script_manager.update(&mut game_data);
+        script_manager.update(&mut game_data);
+        assert_eq!(story_vars(&game_data)["old_value_was_nil"], 3);
+    }
+}
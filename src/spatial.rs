@@ -0,0 +1,152 @@
+use crate::components::{AreaTrigger, Collision, InteractionTrigger, Position, Teleport};
+use crate::ecs::EntityId;
+use crate::math::{MapPos, MapUnits, Vec2};
+use crate::misc::Aabb;
+use std::collections::HashMap;
+
+// Size of the buckets in map units
+const BUCKET_SIZE: f64 = 4.;
+
+// Spatial hash of entities with positions, per map
+// Entities are indexed by their bounds, which cover all of their hitboxes (collision, area,
+// interaction and teleport), or just their position if they have none
+// Queries return entities whose indexed bounds match, which are as of the last time the index was
+// updated (see update_spatial_index)
+pub struct SpatialIndex {
+    maps: HashMap<String, HashMap<(i32, i32), Vec<EntityId>>>,
+    entries: HashMap<EntityId, (String, Aabb)>,
+}
+
+impl SpatialIndex {
+    pub fn new() -> Self {
+        Self { maps: HashMap::new(), entries: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, id: EntityId, map: &str, bounds: Aabb) {
+        self.remove(id);
+
+        let buckets = self.maps.entry(map.to_string()).or_default();
+        for bucket in buckets_overlapping(&bounds) {
+            buckets.entry(bucket).or_default().push(id);
+        }
+        self.entries.insert(id, (map.to_string(), bounds));
+    }
+
+    pub fn remove(&mut self, id: EntityId) {
+        let Some((map, bounds)) = self.entries.remove(&id) else {
+            return;
+        };
+
+        let buckets = self.maps.get_mut(&map).expect("entries are in buckets");
+        for bucket in buckets_overlapping(&bounds) {
+            if let Some(ids) = buckets.get_mut(&bucket) {
+                ids.retain(|i| *i != id);
+                if ids.is_empty() {
+                    buckets.remove(&bucket);
+                }
+            }
+        }
+    }
+
+    // For systems that move entities, to keep the index exact for the rest of the system
+    pub fn move_by(&mut self, id: EntityId, offset: Vec2<f64, MapUnits>) {
+        if let Some((map, bounds)) = self.entries.get(&id).cloned() {
+            let moved = Aabb {
+                top: bounds.top + offset.y,
+                bottom: bounds.bottom + offset.y,
+                left: bounds.left + offset.x,
+                right: bounds.right + offset.x,
+            };
+            self.insert(id, &map, moved);
+        }
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = EntityId> {
+        self.entries.keys().copied()
+    }
+
+    // Entities whose bounds intersect the region
+    pub fn query_region(&self, map: &str, region: &Aabb) -> Vec<EntityId> {
+        let Some(buckets) = self.maps.get(map) else {
+            return Vec::new();
+        };
+
+        let mut ids: Vec<EntityId> = buckets_overlapping(region)
+            .filter_map(|bucket| buckets.get(&bucket))
+            .flatten()
+            .copied()
+            .filter(|id| self.entries[id].1.intersects(region))
+            .collect();
+        // (Entities in more than one bucket are found more than once)
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    // Entities whose bounds contain the point
+    pub fn query_point(&self, map: &str, point: MapPos) -> Vec<EntityId> {
+        let Some(ids) = self.maps.get(map).and_then(|buckets| buckets.get(&bucket_at(point)))
+        else {
+            return Vec::new();
+        };
+
+        ids.iter().copied().filter(|id| self.entries[id].1.contains(&point)).collect()
+    }
+
+    // The entity whose bounds' center is closest to the point, within max distance
+    pub fn nearest(
+        &self,
+        map: &str,
+        point: MapPos,
+        max_distance: f64,
+        filter: impl Fn(EntityId) -> bool,
+    ) -> Option<EntityId> {
+        let region = Aabb::new(point, Vec2::new(max_distance * 2., max_distance * 2.));
+        self.query_region(map, &region)
+            .into_iter()
+            .filter(|id| filter(*id))
+            .map(|id| {
+                let center = self.entries[&id].1.center();
+                (id, ((center.x - point.x).powi(2) + (center.y - point.y).powi(2)).sqrt())
+            })
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).expect("not nan"))
+            .map(|(id, _)| id)
+    }
+}
+
+// The bounds an entity is indexed by
+pub fn entity_bounds(
+    position: &Position,
+    collision: Option<&Collision>,
+    area: Option<&AreaTrigger>,
+    interaction: Option<&InteractionTrigger>,
+    teleport: Option<&Teleport>,
+) -> Aabb {
+    [
+        collision.map(|c| c.hitbox),
+        area.map(|a| a.hitbox),
+        interaction.map(|i| i.hitbox),
+        teleport.map(|t| t.hitbox),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|hitbox| Aabb::new(position.map_pos, hitbox))
+    .reduce(|a, b| Aabb {
+        top: a.top.min(b.top),
+        bottom: a.bottom.max(b.bottom),
+        left: a.left.min(b.left),
+        right: a.right.max(b.right),
+    })
+    .unwrap_or(Aabb::new(position.map_pos, Vec2::default()))
+}
+
+fn bucket_at(point: MapPos) -> (i32, i32) {
+    ((point.x / BUCKET_SIZE).floor() as i32, (point.y / BUCKET_SIZE).floor() as i32)
+}
+
+fn buckets_overlapping(aabb: &Aabb) -> impl Iterator<Item = (i32, i32)> {
+    let (left, top) = bucket_at(Vec2::new(aabb.left, aabb.top));
+    let (right, bottom) = bucket_at(Vec2::new(aabb.right, aabb.bottom));
+    (left..=right).flat_map(move |x| (top..=bottom).map(move |y| (x, y)))
+}
@@ -1,54 +1,129 @@
+use crate::UiData;
 use crate::components::{
-    AnimationComp, AreaTrigger, Camera, CharacterAnims, Collision, CollisionTrigger,
-    DualStateAnimationState, DualStateAnims, Facing, Name, PlaybackState, Position, SfxEmitter,
-    SineOffsetAnimation, SpriteComp, Velocity, Walking,
+    AnimationComp, AreaTrigger, Camera, CharacterAnims, Children, Collision, CollisionTrigger,
+    Door, DoorState, DualStateAnimationState, DualStateAnims, Facing, InteractionTrigger, Parent,
+    PlaybackState, Position, SfxEmitter, SineOffsetAnimation, SpriteComp, Teleport,
+    TeleportDestination, Teleporting, Velocity, Walking,
 };
 use crate::data::PLAYER_ENTITY_NAME;
-use crate::ecs::{Ecs, EntityId};
-use crate::math::{CellPos, MapUnits, Rect, Vec2};
-use crate::misc::{Aabb, Direction};
+use crate::ecs::{Changed, Ecs, EntityId, Or, With, Without};
+use crate::events::{
+    AnimationFinished, EnteredArea, ExitedArea, Interacted, MapChanged, WalkFinished,
+};
+use crate::math::{MapUnits, Rect, Vec2};
+use crate::misc::{Aabb, Direction, Musics, SoundEffects, StoryVars};
+use crate::schedule::{Scheduler, Stage};
 use crate::script::{self, ScriptManager};
-use crate::world::World;
-use crate::{GameData, MessageWindow, UiData};
-use sdl2::mixer::{Chunk, Music};
+use crate::spatial::{self, SpatialIndex};
+use crate::world::{CollisionKind, World, WorldPos};
 use std::collections::HashMap;
 use std::time::Duration;
 use tap::{TapFallible, TapOptional};
 
-pub fn update(
-    game_data: &mut GameData,
-    ui_data: &mut UiData,
-    script_manager: &mut ScriptManager,
-    player_movement_locked: &mut bool,
-    running: &mut bool,
-    musics: &HashMap<String, Music<'_>>,
-    sound_effects: &HashMap<String, Chunk>,
-    delta: Duration,
-) {
-    start_auto_scripts(script_manager, &game_data.auto_scripts);
-    start_area_trigger_scripts(script_manager, &game_data.ecs);
-    #[rustfmt::skip]
-    script_manager.update(
-        game_data, ui_data, player_movement_locked, running, musics, sound_effects,
-    );
-
-    stop_player_movement_when_message_window_open(&game_data.ecs, &ui_data.message_window);
-
-    set_velocity_from_walking(&game_data.ecs);
-    apply_velocity_to_position(&game_data.ecs);
-    start_collision_trigger_scripts(&game_data.ecs, script_manager);
-    resolve_collisions_with_tiles(&game_data.ecs, &game_data.world);
-    resolve_collisions_with_entities(&game_data.ecs);
-    end_walking_if_destination_reached(&game_data.ecs);
-
-    update_camera(&game_data.ecs, &game_data.world);
-
-    update_character_animations(&game_data.ecs);
-    update_dual_state_animations(&game_data.ecs);
-    play_animations_and_set_sprites(&game_data.ecs, delta);
-
-    update_sfx_emitting_entities(&game_data.ecs, sound_effects);
-    end_sine_offset_animations(&mut game_data.ecs);
+// Fractions of the normal speed on slow terrain and in water
+const SLOW_TERRAIN_SPEED: f64 = 0.5;
+const WADING_SPEED: f64 = 0.35;
+
+pub fn add_systems(scheduler: &mut Scheduler) {
+    scheduler.add_system(Stage::Input, "stop_player_movement_while_teleporting", |c| {
+        stop_player_movement_while_teleporting(&c.game_data.ecs)
+    });
+
+    scheduler.add_system(Stage::Scripts, "start_auto_scripts", |c| {
+        start_auto_scripts(c.script_manager, &c.game_data.auto_scripts)
+    });
+    scheduler.add_system(Stage::Scripts, "start_area_trigger_scripts", |c| {
+        start_area_trigger_scripts(c.script_manager, &c.game_data.ecs)
+    });
+    scheduler
+        .add_system(Stage::Scripts, "update_scripts", |c| c.script_manager.update(c.game_data));
+    // After the scripts, so that the player stops on the same frame that a script opens a message
+    scheduler.add_system(Stage::Scripts, "stop_player_movement_when_message_window_open", |c| {
+        stop_player_movement_when_message_window_open(&c.game_data.ecs)
+    });
+    // Doors react to interactions after the interaction scripts start, so that the scripts see the
+    // state the door was in when interacted with
+    scheduler.add_system(Stage::Scripts, "interact_with_doors", |c| {
+        interact_with_doors(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Scripts, "update_door_collisions", |c| {
+        update_door_collisions(&c.game_data.ecs)
+    });
+
+    scheduler.add_system(Stage::Movement, "set_velocity_from_walking", |c| {
+        set_velocity_from_walking(&c.game_data.ecs, &c.game_data.world)
+    });
+    scheduler.add_system(Stage::Movement, "apply_velocity_to_position", |c| {
+        apply_velocity_to_position(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Movement, "update_spatial_index", |c| {
+        update_spatial_index(&c.game_data.ecs)
+    });
+
+    // Collision triggers are checked before collisions are resolved, or the player would be
+    // pushed out of a solid trigger before ever touching it
+    // Walking ends after collisions are resolved, so that the final position is a resolved one
+    scheduler.add_system(Stage::Collision, "start_collision_trigger_scripts", |c| {
+        start_collision_trigger_scripts(&c.game_data.ecs, c.script_manager)
+    });
+    scheduler.add_system(Stage::Collision, "resolve_collisions_with_tiles", |c| {
+        resolve_collisions_with_tiles(&c.game_data.ecs, &c.game_data.world)
+    });
+    scheduler.add_system(Stage::Collision, "resolve_collisions_with_entities", |c| {
+        resolve_collisions_with_entities(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Collision, "end_walking_if_destination_reached", |c| {
+        end_walking_if_destination_reached(&c.game_data.ecs)
+    });
+    // Teleports go last, so that the player arrives at exactly the destination
+    scheduler.add_system(Stage::Collision, "update_teleporting", |c| {
+        update_teleporting(&mut c.game_data.ecs, c.delta)
+    });
+    scheduler
+        .add_system(Stage::Collision, "start_teleports", |c| start_teleports(&mut c.game_data.ecs));
+
+    scheduler
+        .add_system(Stage::Hierarchy, "update_children", |c| update_children(&mut c.game_data.ecs));
+    scheduler.add_system(Stage::Hierarchy, "propagate_to_children", |c| {
+        propagate_to_children(&c.game_data.ecs)
+    });
+    // Again, so that the index is up to date for input and scripts at the start of the next frame
+    scheduler.add_system(Stage::Hierarchy, "update_spatial_index_after_hierarchy", |c| {
+        update_spatial_index(&c.game_data.ecs)
+    });
+
+    scheduler.add_system(Stage::Camera, "update_camera", |c| {
+        update_camera(&c.game_data.ecs, &c.game_data.world)
+    });
+    scheduler.add_system(Stage::Camera, "send_map_changed_events", |c| {
+        send_map_changed_events(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Camera, "apply_map_properties", |c| {
+        apply_map_properties(&c.game_data.ecs, &c.game_data.world, c.script_manager)
+    });
+
+    scheduler.add_system(Stage::Animation, "advance_tile_animations", |c| {
+        advance_tile_animations(&mut c.game_data.world, c.delta)
+    });
+    scheduler.add_system(Stage::Animation, "update_door_animations", |c| {
+        update_door_animations(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Animation, "update_character_animations", |c| {
+        update_character_animations(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Animation, "update_dual_state_animations", |c| {
+        update_dual_state_animations(&c.game_data.ecs)
+    });
+    scheduler.add_system(Stage::Animation, "play_animations_and_set_sprites", |c| {
+        play_animations_and_set_sprites(&c.game_data.ecs, c.delta)
+    });
+    scheduler.add_system(Stage::Animation, "end_sine_offset_animations", |c| {
+        end_sine_offset_animations(&mut c.game_data.ecs)
+    });
+
+    scheduler.add_system(Stage::Audio, "update_sfx_emitting_entities", |c| {
+        update_sfx_emitting_entities(&c.game_data.ecs)
+    });
 }
 
 // ------------------------------------------------------------------
@@ -75,18 +150,40 @@ fn start_auto_scripts(script_manager: &mut ScriptManager, auto_scripts: &Vec<Str
 }
 
 fn start_area_trigger_scripts(script_manager: &mut ScriptManager, ecs: &Ecs) {
-    let Some((player_aabb, player_map)) = ecs
-        .query_one_with_name::<(&Position, &Collision)>(PLAYER_ENTITY_NAME)
-        .map(|(pos, coll)| (Aabb::new(pos.map_pos, coll.hitbox), pos.map.clone()))
+    let Some((player_id, player_aabb, player_map)) = ecs
+        .query_one::<(EntityId, &Position, &Collision)>(PLAYER_ENTITY_NAME)
+        .map(|(id, pos, coll)| (id, Aabb::new(pos.map_pos, coll.hitbox), pos.map.clone()))
     else {
         return;
     };
 
-    for (_, area) in ecs
-        .query::<(&Position, &AreaTrigger)>()
-        .filter(|(pos, _)| pos.map == player_map)
-        .filter(|(pos, area)| Aabb::new(pos.map_pos, area.hitbox).intersects(&player_aabb))
-    {
+    let inside_areas: Vec<EntityId> = ecs
+        .resource::<SpatialIndex>()
+        .query_region(&player_map, &player_aabb)
+        .into_iter()
+        .filter(|id| {
+            ecs.query_one_with_id::<(&Position, &AreaTrigger)>(*id).is_some_and(|(pos, area)| {
+                Aabb::new(pos.map_pos, area.hitbox).intersects(&player_aabb)
+            })
+        })
+        .collect();
+
+    for (area_id, mut area) in ecs.query::<(EntityId, &mut AreaTrigger)>() {
+        let inside = inside_areas.contains(&area_id);
+
+        if inside != area.player_inside {
+            area.player_inside = inside;
+            if inside {
+                ecs.send_event(EnteredArea { entity: player_id, area: area_id });
+            } else {
+                ecs.send_event(ExitedArea { entity: player_id, area: area_id });
+            }
+        }
+
+        if !inside {
+            continue;
+        }
+
         if let Ok(source) = area
             .script_source
             .get_source()
@@ -97,31 +194,136 @@ fn start_area_trigger_scripts(script_manager: &mut ScriptManager, ecs: &Ecs) {
     }
 }
 
+// ------------------------------------------------------------------
+// Doors
+// ------------------------------------------------------------------
+
+// Interacting toggles between open and closed, and opens a locked door if the key story var is set
+fn interact_with_doors(ecs: &Ecs) {
+    for Interacted { entity } in ecs.read_events::<Interacted>() {
+        let Some(mut door) = ecs.query_one_with_id::<&mut Door>(entity) else {
+            continue;
+        };
+
+        let has_key = door
+            .key_story_var
+            .as_ref()
+            .is_some_and(|key| ecs.resource::<StoryVars>().get(key).is_some_and(|val| val != 0));
+        let new_state = match door.state {
+            // (Not on top of anything solid, such as the player standing in the doorway)
+            DoorState::Open if door_blocked(ecs, entity) => DoorState::Open,
+            DoorState::Open => DoorState::Closed,
+            DoorState::Closed => DoorState::Open,
+            DoorState::Locked if has_key => DoorState::Open,
+            DoorState::Locked => DoorState::Locked,
+        };
+        if new_state != door.state {
+            door.state = new_state;
+        }
+    }
+}
+
+// Whether a solid entity overlaps the door's collision, so that it can't close
+pub fn door_blocked(ecs: &Ecs, door_id: EntityId) -> bool {
+    let Some((position, collision)) = ecs.query_one_with_id::<(&Position, &Collision)>(door_id)
+    else {
+        return false;
+    };
+    let aabb = Aabb::new(position.map_pos, collision.hitbox);
+
+    ecs.resource::<SpatialIndex>().query_region(&position.map, &aabb).into_iter().any(|other_id| {
+        other_id != door_id
+            && ecs.query_one_with_id::<(&Position, &Collision)>(other_id).is_some_and(
+                |(other_position, other_collision)| {
+                    other_collision.solid
+                        && aabb
+                            .intersects(&Aabb::new(other_position.map_pos, other_collision.hitbox))
+                },
+            )
+    })
+}
+
+fn update_door_collisions(ecs: &Ecs) {
+    for (door, mut collision, ()) in ecs.query::<(&Door, &mut Collision, Changed<Door>)>() {
+        let solid = door.state != DoorState::Open;
+        if collision.solid != solid {
+            collision.solid = solid;
+        }
+    }
+}
+
 // ------------------------------------------------------------------
 // Animation
 // ------------------------------------------------------------------
 
+fn advance_tile_animations(world: &mut World, delta: Duration) {
+    world.tile_animation_elapsed += delta;
+}
+
+fn update_door_animations(ecs: &Ecs) {
+    for (mut anim_comp, mut door) in ecs.query::<(&mut AnimationComp, &mut Door)>() {
+        if anim_comp.forced {
+            continue;
+        }
+
+        if door.animated_state != Some(door.state) {
+            let transition = match (door.animated_state, door.state) {
+                (Some(DoorState::Closed | DoorState::Locked), DoorState::Open) => {
+                    door.opening.clone()
+                }
+                (Some(DoorState::Open), DoorState::Closed | DoorState::Locked) => {
+                    door.closing.clone()
+                }
+                _ => None,
+            };
+            door.animated_state = Some(door.state);
+
+            match transition {
+                Some(clip) => {
+                    anim_comp.clip = clip;
+                    anim_comp.start(false);
+                }
+                None => {
+                    anim_comp.clip = door.clip(door.state).clone();
+                    anim_comp.start(true);
+                }
+            }
+        } else if anim_comp.state == PlaybackState::Stopped {
+            // The opening or closing clip finished
+            anim_comp.clip = door.clip(door.state).clone();
+            anim_comp.start(true);
+        }
+    }
+}
+
 fn update_character_animations(ecs: &Ecs) {
-    for (mut anim_comp, char_anims, facing, walk_comp) in
-        ecs.query::<(&mut AnimationComp, &CharacterAnims, &Facing, &Walking)>()
+    // Only entities whose animation might need to change
+    // (Which is why the anim comp is only mutated when something actually changes)
+    type AnyChanged =
+        Or<(Changed<AnimationComp>, Changed<CharacterAnims>, Changed<Facing>, Changed<Walking>)>;
+
+    for (mut anim_comp, char_anims, facing, walk_comp, ()) in
+        ecs.query::<(&mut AnimationComp, &CharacterAnims, &Facing, &Walking, AnyChanged)>()
     {
         if anim_comp.forced {
             continue;
         }
 
-        anim_comp.clip = match facing.0 {
+        let clip = match facing.0 {
             Direction::Up => &char_anims.up,
             Direction::Down => &char_anims.down,
             Direction::Left => &char_anims.left,
             Direction::Right => &char_anims.right,
+        };
+        if anim_comp.clip != *clip {
+            anim_comp.clip = clip.clone();
         }
-        .clone();
 
         if walk_comp.speed > 0. {
             if anim_comp.state == PlaybackState::Stopped {
                 anim_comp.start(true);
             }
-        } else {
+        } else if anim_comp.state != PlaybackState::Stopped {
             anim_comp.stop();
         }
     }
@@ -149,25 +351,31 @@ fn update_dual_state_animations(ecs: &Ecs) {
             _ => {}
         }
 
-        anim_comp.clip = match dual_anims.state {
+        let clip = match dual_anims.state {
             S::First => &dual_anims.first,
             S::FirstToSecond => &dual_anims.first_to_second,
             S::Second => &dual_anims.second,
             S::SecondToFirst => &dual_anims.second_to_first,
+        };
+        if anim_comp.clip != *clip {
+            anim_comp.clip = clip.clone();
         }
-        .clone();
     }
 }
 
 fn play_animations_and_set_sprites(ecs: &Ecs, delta: Duration) {
-    for (mut anim_comp, mut sprite_comp) in ecs.query::<(&mut AnimationComp, &mut SpriteComp)>() {
+    for (id, mut anim_comp, mut sprite_comp) in
+        ecs.query::<(EntityId, &mut AnimationComp, &mut SpriteComp)>()
+    {
         // Should anim_comp.clip be an Option? Or is "no clip" just an empty clip?
         if anim_comp.clip.frames.is_empty() {
             continue;
         }
 
+        // (Advancing the time alone isn't a change, so that Changed<AnimationComp> only matches
+        // when the clip or playback state changes)
         if anim_comp.state == PlaybackState::Playing {
-            anim_comp.elapsed += delta;
+            anim_comp.bypass_change_detection().elapsed += delta;
         }
 
         let clip = &anim_comp.clip;
@@ -180,10 +388,15 @@ fn play_animations_and_set_sprites(ecs: &Ecs, delta: Duration) {
             (elapsed % duration / clip.seconds_per_frame).floor() as usize
         };
         let sprite = clip.frames.get(frame_index).expect("modulo");
-        sprite_comp.sprite = Some(sprite.clone());
+        if sprite_comp.sprite.as_ref() != Some(sprite) {
+            sprite_comp.sprite = Some(sprite.clone());
+        }
 
-        if finished {
+        // (A finished animation stays finished until started again, so only the first time
+        // counts)
+        if finished && anim_comp.state != PlaybackState::Stopped {
             anim_comp.stop();
+            ecs.send_event(AnimationFinished { entity: id });
         }
     }
 }
@@ -192,13 +405,35 @@ fn play_animations_and_set_sprites(ecs: &Ecs, delta: Duration) {
 // Movement and Collision
 // ------------------------------------------------------------------
 
-fn set_velocity_from_walking(ecs: &Ecs) {
-    for (mut velocity, walking) in ecs.query::<(&mut Velocity, &Walking)>() {
+fn set_velocity_from_walking(ecs: &Ecs, world: &World) {
+    for (mut velocity, walking, position, collision) in
+        ecs.query::<(&mut Velocity, &Walking, Option<&Position>, Option<&Collision>)>()
+    {
+        // Solid entities are slowed while any part of their hitbox is on slow terrain or water
+        // (By the slowest of them)
+        let terrain_speed = match (position, collision) {
+            (Some(position), Some(collision)) if collision.solid => {
+                world.maps.get(&position.map).map_or(1., |map| {
+                    let aabb = Aabb::new(position.map_pos, collision.hitbox);
+                    map.collision_aabbs_in_region(&aabb)
+                        .filter(|(cell_aabb, _)| aabb.intersects(cell_aabb))
+                        .map(|(_, kind)| match kind {
+                            CollisionKind::Slow => SLOW_TERRAIN_SPEED,
+                            CollisionKind::Water => WADING_SPEED,
+                            _ => 1.,
+                        })
+                        .fold(1., f64::min)
+                })
+            }
+            _ => 1.,
+        };
+        let speed = walking.speed * terrain_speed;
+
         velocity.0 = match walking.direction {
-            Direction::Up => Vec2::new(0.0, -walking.speed),
-            Direction::Down => Vec2::new(0.0, walking.speed),
-            Direction::Left => Vec2::new(-walking.speed, 0.0),
-            Direction::Right => Vec2::new(walking.speed, 0.0),
+            Direction::Up => Vec2::new(0.0, -speed),
+            Direction::Down => Vec2::new(0.0, speed),
+            Direction::Left => Vec2::new(-speed, 0.0),
+            Direction::Right => Vec2::new(speed, 0.0),
         }
     }
 }
@@ -211,7 +446,7 @@ fn apply_velocity_to_position(ecs: &Ecs) {
 
 fn start_collision_trigger_scripts(ecs: &Ecs, script_manager: &mut ScriptManager) {
     let Some((player_id, player_position, player_collision)) =
-        ecs.query_one_with_name::<(EntityId, &Position, &Collision)>(PLAYER_ENTITY_NAME)
+        ecs.query_one::<(EntityId, &Position, &Collision)>(PLAYER_ENTITY_NAME)
     else {
         return;
     };
@@ -222,10 +457,15 @@ fn start_collision_trigger_scripts(ecs: &Ecs, script_manager: &mut ScriptManager
 
     let player_aabb = Aabb::new(player_position.map_pos, player_collision.hitbox);
 
-    for (other_position, other_collision, trigger) in
-        ecs.query_except::<(&Position, &Collision, &CollisionTrigger)>(player_id)
+    for other_id in ecs.resource::<SpatialIndex>().query_region(&player_position.map, &player_aabb)
     {
-        if other_position.map != player_position.map || !other_collision.solid {
+        let Some((other_position, other_collision, trigger)) = ecs
+            .query_one_with_id::<(&Position, &Collision, &CollisionTrigger)>(other_id)
+            .filter(|_| other_id != player_id)
+        else {
+            continue;
+        };
+        if !other_collision.solid {
             continue;
         }
 
@@ -243,8 +483,11 @@ fn start_collision_trigger_scripts(ecs: &Ecs, script_manager: &mut ScriptManager
 }
 
 fn resolve_collisions_with_tiles(ecs: &Ecs, world: &World) {
+    let mut spatial_index = ecs.resource_mut::<SpatialIndex>();
+
     // This only works for entities with velocities
-    for (mut position, collision, velocity) in ecs.query::<(&mut Position, &Collision, &Velocity)>()
+    for (id, mut position, collision, velocity) in
+        ecs.query::<(EntityId, &mut Position, &Collision, &Velocity)>()
     {
         if !collision.solid {
             continue;
@@ -258,32 +501,27 @@ fn resolve_collisions_with_tiles(ecs: &Ecs, world: &World) {
 
         let mut aabb = Aabb::new(map_pos, collision.hitbox);
 
-        // TODO bug: some out of bounds positions have collision and some do not
-
-        // Resolve collisions with the 9 cells centered around new position
-        let new_cellpos = map_pos.to_cell_units();
-        let cellposes_to_check: [CellPos; 9] = [
-            Vec2::new(new_cellpos.x - 1, new_cellpos.y - 1),
-            Vec2::new(new_cellpos.x, new_cellpos.y - 1),
-            Vec2::new(new_cellpos.x + 1, new_cellpos.y - 1),
-            Vec2::new(new_cellpos.x - 1, new_cellpos.y),
-            Vec2::new(new_cellpos.x, new_cellpos.y),
-            Vec2::new(new_cellpos.x + 1, new_cellpos.y),
-            Vec2::new(new_cellpos.x - 1, new_cellpos.y + 1),
-            Vec2::new(new_cellpos.x, new_cellpos.y + 1),
-            Vec2::new(new_cellpos.x + 1, new_cellpos.y + 1),
-        ];
-        for cell_aabb in
-            cellposes_to_check.iter().flat_map(|cp| map.collision_aabbs_for_cell(*cp)).flatten()
-        {
-            aabb.resolve_collision(&cell_aabb, velocity.0);
+        // Resolve collisions with the collision cells within a map unit around new position
+        let region = Aabb {
+            top: aabb.top - 1.,
+            bottom: aabb.bottom + 1.,
+            left: aabb.left - 1.,
+            right: aabb.right + 1.,
+        };
+        for (cell_aabb, kind) in map.collision_aabbs_in_region(&region) {
+            if kind.blocks(velocity.0) {
+                aabb.resolve_collision(&cell_aabb, velocity.0);
+            }
         }
 
+        spatial_index.move_by(id, aabb.center() - position.map_pos);
         position.map_pos = aabb.center();
     }
 }
 
 fn resolve_collisions_with_entities(ecs: &Ecs) {
+    let mut spatial_index = ecs.resource_mut::<SpatialIndex>();
+
     // This only works for entities with velocities
     for (id, mut position, collision, velocity) in
         ecs.query::<(EntityId, &mut Position, &Collision, &Velocity)>()
@@ -294,20 +532,163 @@ fn resolve_collisions_with_entities(ecs: &Ecs) {
 
         let mut aabb = Aabb::new(position.map_pos, collision.hitbox);
 
-        for (other_pos, other_coll) in ecs.query_except::<(&Position, &Collision)>(id) {
-            if other_pos.map != position.map || !other_coll.solid {
+        // Only entities near enough to collide
+        for other_id in spatial_index.query_region(&position.map, &aabb) {
+            let Some((other_pos, other_coll)) = ecs
+                .query_one_with_id::<(&Position, &Collision)>(other_id)
+                .filter(|_| other_id != id)
+            else {
+                continue;
+            };
+            if !other_coll.solid {
                 continue;
             }
 
             aabb.resolve_collision(&Aabb::new(other_pos.map_pos, other_coll.hitbox), velocity.0);
         }
 
+        spatial_index.move_by(id, aabb.center() - position.map_pos);
         position.map_pos = aabb.center();
     }
 }
 
+fn start_teleports(ecs: &mut Ecs) {
+    let Some((player_id, player_position)) = ecs
+        .query_one::<(EntityId, &Position, Without<Teleporting>)>(PLAYER_ENTITY_NAME)
+        .map(|(id, position, ())| (id, position.0.clone()))
+    else {
+        return;
+    };
+
+    let inside_teleports = teleports_at(ecs, &player_position);
+
+    let mut entered = None;
+    for (teleport_id, mut teleport) in ecs.query::<(EntityId, &mut Teleport)>() {
+        let inside = inside_teleports.contains(&teleport_id);
+        if inside != teleport.player_inside {
+            teleport.player_inside = inside;
+            if inside && entered.is_none() {
+                entered = Some(teleport.clone());
+            }
+        }
+    }
+    let Some(teleport) = entered else {
+        return;
+    };
+
+    if let Some(condition) = &teleport.condition
+        && !script::evaluate_story_var_condition(condition, &ecs.resource::<StoryVars>())
+            .tap_err(|e| {
+                log::error!(once = true; "Invalid story var condition `{condition}` (err: {e})")
+            })
+            .unwrap_or(false)
+    {
+        return;
+    }
+
+    let destination = match &teleport.destination {
+        TeleportDestination::WorldPos(destination) => destination.clone(),
+        TeleportDestination::Entity(entity) => {
+            let Some(position) = ecs.query_one::<&Position>(entity) else {
+                log::error!(once = true; "Invalid teleport destination: {entity}");
+                return;
+            };
+            position.0.clone()
+        }
+    };
+
+    match teleport.fade {
+        Some(fade) if fade > 0. => ecs.add_component(
+            player_id,
+            Teleporting {
+                destination,
+                facing: teleport.facing,
+                fade: Duration::from_secs_f64(fade),
+                elapsed: Duration::ZERO,
+                arrived: false,
+            },
+        ),
+        _ => arrive_at_teleport_destination(ecs, player_id, &destination, teleport.facing),
+    }
+}
+
+// The entity is moved once fully faded out
+fn update_teleporting(ecs: &mut Ecs, delta: Duration) {
+    let mut arrivals = Vec::new();
+    for (id, mut teleporting) in ecs.query::<(EntityId, &mut Teleporting)>() {
+        teleporting.elapsed += delta;
+
+        if !teleporting.arrived && teleporting.elapsed >= teleporting.fade {
+            teleporting.arrived = true;
+            arrivals.push((id, teleporting.destination.clone(), teleporting.facing));
+        }
+
+        if teleporting.elapsed >= teleporting.fade * 2 {
+            ecs.remove_component_deferred::<Teleporting>(id);
+        }
+    }
+
+    for (id, destination, facing) in arrivals {
+        arrive_at_teleport_destination(ecs, id, &destination, facing);
+    }
+    ecs.flush_deferred_mutations();
+}
+
+fn arrive_at_teleport_destination(
+    ecs: &Ecs,
+    id: EntityId,
+    destination: &WorldPos,
+    facing: Option<Direction>,
+) {
+    let Some(old_map) = ecs
+        .query_one_with_id::<&mut Position>(id)
+        .map(|mut position| std::mem::replace(&mut position.0, destination.clone()).map)
+    else {
+        return;
+    };
+    if let Some(facing) = facing
+        && let Some(mut facing_component) = ecs.query_one_with_id::<&mut Facing>(id)
+    {
+        facing_component.0 = facing;
+    }
+
+    // Arriving inside a teleport doesn't count as entering it
+    let inside_teleports = teleports_at(ecs, destination);
+    for (teleport_id, mut teleport) in ecs.query::<(EntityId, &mut Teleport)>() {
+        if inside_teleports.contains(&teleport_id) && !teleport.player_inside {
+            teleport.player_inside = true;
+        }
+    }
+
+    // Sent here rather than left to the camera, which might not be following the entity
+    // (A camera that is gets its previous map updated, so that it doesn't send it again)
+    if old_map != destination.map {
+        if let Some(mut camera) = ecs.query::<&mut Camera>().next()
+            && camera.target_entity.as_ref().and_then(|e| ecs.find_entity(e)) == Some(id)
+        {
+            camera.previous_map = Some(destination.map.clone());
+        }
+        ecs.send_event(MapChanged { old_map: Some(old_map), new_map: destination.map.clone() });
+    }
+}
+
+// Teleports whose hitbox contains the position
+fn teleports_at(ecs: &Ecs, world_pos: &WorldPos) -> Vec<EntityId> {
+    ecs.resource::<SpatialIndex>()
+        .query_point(&world_pos.map, world_pos.map_pos)
+        .into_iter()
+        .filter(|id| {
+            ecs.query_one_with_id::<(&Position, &Teleport)>(*id).is_some_and(|(pos, teleport)| {
+                Aabb::new(pos.map_pos, teleport.hitbox).contains(&world_pos.map_pos)
+            })
+        })
+        .collect()
+}
+
 fn end_walking_if_destination_reached(ecs: &Ecs) {
-    for (mut position, mut walking) in ecs.query::<(&mut Position, &mut Walking)>() {
+    for (id, mut position, mut walking, facing) in
+        ecs.query::<(EntityId, &mut Position, &mut Walking, Option<&mut Facing>)>()
+    {
         if let Some(destination) = walking.destination {
             let passed_destination = match walking.direction {
                 Direction::Up => position.map_pos.y < destination.y,
@@ -317,26 +698,154 @@ fn end_walking_if_destination_reached(ecs: &Ecs) {
             };
             if passed_destination {
                 position.map_pos = destination;
+
+                // On to the next waypoint, if any
+                if let Some(waypoint) = walking.waypoints.pop_front() {
+                    walking.direction = Direction::towards(destination, waypoint);
+                    walking.destination = Some(waypoint);
+                    if let Some(mut facing) = facing {
+                        facing.0 = walking.direction;
+                    }
+                    continue;
+                }
+
                 walking.speed = 0.;
                 walking.destination = None;
+                ecs.send_event(WalkFinished { entity: id });
             }
         }
     }
 }
 
+// Reindexes entities whose position or hitboxes changed since the system last ran
+fn update_spatial_index(ecs: &Ecs) {
+    type AnyChanged = Or<(
+        Changed<Position>,
+        Changed<Collision>,
+        Changed<AreaTrigger>,
+        Changed<InteractionTrigger>,
+        Changed<Teleport>,
+    )>;
+
+    let mut spatial_index = ecs.resource_mut::<SpatialIndex>();
+
+    // Entities that were removed or lost their position
+    let removed: Vec<EntityId> = spatial_index
+        .ids()
+        .filter(|id| ecs.query_one_with_id::<With<Position>>(*id).is_none())
+        .collect();
+    for id in removed {
+        spatial_index.remove(id);
+    }
+
+    for (id, position, (collision, area, interaction, teleport), ()) in ecs.query::<(
+        EntityId,
+        &Position,
+        (Option<&Collision>, Option<&AreaTrigger>, Option<&InteractionTrigger>, Option<&Teleport>),
+        AnyChanged,
+    )>() {
+        let bounds = spatial::entity_bounds(
+            &position,
+            collision.as_deref(),
+            area.as_deref(),
+            interaction.as_deref(),
+            teleport.as_deref(),
+        );
+        spatial_index.insert(id, &position.map, bounds);
+    }
+}
+
+// ------------------------------------------------------------------
+// Hierarchy
+// ------------------------------------------------------------------
+
+fn update_children(ecs: &mut Ecs) {
+    // Parents are resolved every frame, since a parent name might refer to an entity that didn't
+    // exist yet when the child was added
+    let mut children_of: HashMap<EntityId, Vec<EntityId>> = HashMap::new();
+    for (id, parent) in ecs.query::<(EntityId, &Parent)>() {
+        match ecs.find_entity(&parent.entity).filter(|parent_id| *parent_id != id) {
+            Some(parent_id) => children_of.entry(parent_id).or_default().push(id),
+            None => log::error!(once = true; "Invalid parent: {}", parent.entity),
+        }
+    }
+
+    // Only touch Children components that are actually different, so they aren't needlessly
+    // marked as changed
+    for (id, _) in ecs.query::<(EntityId, With<Children>)>() {
+        if !children_of.contains_key(&id) {
+            ecs.remove_component_deferred::<Children>(id);
+        }
+    }
+    for (parent_id, children) in children_of {
+        match ecs.query_one_with_id::<&mut Children>(parent_id) {
+            Some(mut c) if c.0 != children => c.0 = children,
+            Some(_) => {}
+            None => ecs.add_component_deferred(parent_id, Children(children)),
+        }
+    }
+    ecs.flush_deferred_mutations();
+}
+
+fn propagate_to_children(ecs: &Ecs) {
+    // Walk down from every root, so that parents are always updated before their children
+    // (Entities in a parent cycle have no root, and are left alone)
+    for (root_id, _, _) in ecs.query::<(EntityId, With<Children>, Without<Parent>)>() {
+        propagate_to_children_of(ecs, root_id, true);
+    }
+}
+
+fn propagate_to_children_of(ecs: &Ecs, parent_id: EntityId, parent_visible: bool) {
+    let Some(children) = ecs.query_one_with_id::<&Children>(parent_id).map(|c| c.0.clone()) else {
+        return;
+    };
+    let parent_position = ecs.query_one_with_id::<&Position>(parent_id).map(|p| p.clone());
+    // An ancestor without a sprite doesn't hide anything
+    let visible =
+        parent_visible && ecs.query_one_with_id::<&SpriteComp>(parent_id).is_none_or(|s| s.visible);
+
+    for child_id in children {
+        if let Some(parent_position) = &parent_position
+            && let Some((mut position, parent)) =
+                ecs.query_one_with_id::<(&mut Position, &Parent)>(child_id)
+        {
+            let new_position = Position(WorldPos {
+                map: parent_position.map.clone(),
+                map_pos: parent_position.map_pos + parent.offset,
+            });
+            if position.0 != new_position.0 {
+                *position = new_position;
+            }
+        }
+
+        if let Some(mut sprite) = ecs.query_one_with_id::<&mut SpriteComp>(child_id)
+            && sprite.parent_visible != visible
+        {
+            sprite.parent_visible = visible;
+        }
+
+        propagate_to_children_of(ecs, child_id, visible);
+    }
+}
+
 // ------------------------------------------------------------------
 // Misc
 // ------------------------------------------------------------------
 
-fn stop_player_movement_when_message_window_open(
-    ecs: &Ecs,
-    message_window: &Option<MessageWindow>,
-) {
+fn stop_player_movement_when_message_window_open(ecs: &Ecs) {
     // Stop player movement when message window is open, but only if that movement is
     // from player input, not forced
-    if message_window.is_some()
-        && let Some(mut walking_component) =
-            ecs.query_one_with_name::<&mut Walking>(PLAYER_ENTITY_NAME)
+    if ecs.resource::<UiData>().message_window.is_some()
+        && let Some(mut walking_component) = ecs.query_one::<&mut Walking>(PLAYER_ENTITY_NAME)
+        && walking_component.destination.is_none()
+    {
+        walking_component.speed = 0.;
+    }
+}
+
+fn stop_player_movement_while_teleporting(ecs: &Ecs) {
+    if let Some((mut walking_component, ())) =
+        ecs.query_one::<(&mut Walking, With<Teleporting>)>(PLAYER_ENTITY_NAME)
         && walking_component.destination.is_none()
     {
         walking_component.speed = 0.;
@@ -360,14 +869,13 @@ fn update_camera(ecs: &Ecs, world: &World) {
     };
 
     // Update camera position to follow target entity
-    if let Some(target_name) = &camera_component.target_entity
-        && let Some((target_position, _)) = ecs
-            // query_one_with_name does NOT avoid a double borrow
-            // Only query_except and query_one_with_id filter in ways that avoid a double borrow
-            // So we have to query_except(camera_id), then filter results by name
-            .query_except::<(&Position, &Name)>(camera_id)
-            .find(|(_, name)| name.eq(target_name))
-            .tap_none(|| log::error!(once = true; "Invalid camera target: {}", &target_name))
+    // (The camera can't target itself, since its position is already mutably borrowed)
+    if let Some(target) = &camera_component.target_entity
+        && let Some(target_position) = ecs
+            .find_entity(target)
+            .filter(|id| *id != camera_id)
+            .and_then(|id| ecs.query_one_with_id::<&Position>(id))
+            .tap_none(|| log::error!(once = true; "Invalid camera target: {target}"))
     {
         *camera_position = target_position.clone();
     }
@@ -402,11 +910,79 @@ fn update_camera(ecs: &Ecs, world: &World) {
     }
 }
 
+fn send_map_changed_events(ecs: &Ecs) {
+    let Some((position, mut camera)) = ecs.query::<(&Position, &mut Camera)>().next() else {
+        return;
+    };
+
+    if camera.previous_map.as_ref() != Some(&position.map) {
+        let old_map = camera.previous_map.replace(position.map.clone());
+        ecs.send_event(MapChanged { old_map, new_map: position.map.clone() });
+    }
+}
+
+fn apply_map_properties(ecs: &Ecs, world: &World, script_manager: &mut ScriptManager) {
+    for MapChanged { old_map, new_map } in ecs.read_events::<MapChanged>() {
+        let Some(properties) = world.maps.get(&new_map).map(|m| &m.properties) else {
+            continue;
+        };
+        let old_properties =
+            old_map.as_ref().and_then(|m| world.maps.get(m)).map(|m| &m.properties);
+
+        // Music carries on between maps with the same music
+        // (And on maps without music, such as music started by a script)
+        let old_music = old_properties.and_then(|p| p.music.as_ref());
+        if let Some(music_name) = &properties.music
+            && Some(music_name) != old_music
+            && let Some(music) = ecs
+                .resource::<Musics>()
+                .get(music_name)
+                .tap_none(|| log::error!(once = true; "Music doesn't exist: {music_name}"))
+        {
+            let _ = music.play(-1).tap_err(|e| log::error!("Failed to play music (err: {e})"));
+        }
+
+        // Ambient sfx are emitted by the camera, so they're played by update_sfx_emitting_entities
+        if let Some((mut sfx, _)) = ecs.query::<(&mut SfxEmitter, With<Camera>)>().next()
+            && sfx.sfx_name != properties.ambient_sfx
+        {
+            if let Some(channel) = sfx.channel.take() {
+                channel.halt();
+            }
+            sfx.sfx_name = properties.ambient_sfx.clone();
+            sfx.repeat = true;
+        }
+
+        if let Some(clamp) = properties.camera_clamp
+            && let Some(mut camera) = ecs.query::<&mut Camera>().next()
+        {
+            camera.clamp_to_map = clamp;
+        }
+
+        if let Some(script_source) = &properties.on_enter_script
+            && let Ok(source) = script_source
+                .get_source()
+                .tap_err(|e| log::error!(once = true; "Couldn't get script source (err: {e})"))
+        {
+            script_manager.queue_script(&source);
+        }
+    }
+}
+
 // TODO proximity sound
-fn update_sfx_emitting_entities(ecs: &Ecs, sound_effects: &HashMap<String, Chunk>) {
-    let camera_map = ecs.query::<(&Position, &Camera)>().next().map(|(p, _)| p.map.clone());
+fn update_sfx_emitting_entities(ecs: &Ecs) {
+    // Only entities whose sfx might need to start or stop, unless the camera moved to another map
+    type AnyChanged = Or<(Changed<Position>, Changed<SfxEmitter>)>;
+
+    let sound_effects = ecs.resource::<SoundEffects>();
+    let camera_map = ecs.query::<(&Position, With<Camera>)>().next().map(|(p, _)| p.map.clone());
+    let camera_map_changed = !ecs.read_events::<MapChanged>().is_empty();
+
+    for (pos, mut sfx, changed) in ecs.query::<(&Position, &mut SfxEmitter, Option<AnyChanged>)>() {
+        if changed.is_none() && !camera_map_changed {
+            continue;
+        }
 
-    for (pos, mut sfx) in ecs.query::<(&Position, &mut SfxEmitter)>() {
         // If entity is on camera map, and it has an sfx to emit, and the sfx is not playing on
         // any channel, play the sfx
         if let Some(camera_map) = camera_map.as_ref()
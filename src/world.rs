@@ -1,10 +1,15 @@
+use crate::components::ScriptSource;
+use crate::loader::ldtk_entities::{external_script_source, read_field_instance};
 use crate::loader::ldtk_project;
-use crate::math::{CellPos, CellUnits, MapPos, MapUnits, PixelUnits, Vec2};
-use crate::misc::Aabb;
+use crate::math::{CellUnits, MapPos, MapUnits, PixelUnits, Vec2};
+use crate::misc::{Aabb, Direction};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::format as f;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct WorldPos {
     pub map: String,
@@ -25,22 +30,360 @@ impl WorldPos {
 }
 
 pub struct World {
+    // Pixels per map unit, the default grid size of the LDtk project
+    // (Layers with other grid sizes are scaled to it)
+    pub cell_size: u32,
     pub maps: HashMap<String, Map>,
+    // By tileset path, then by the tile placed in the map
+    pub tile_animations: HashMap<String, HashMap<TileId, TileAnimation>>,
+    // Shared by every animated tile, so that all instances of a tile animate in sync
+    pub tile_animation_elapsed: Duration,
+    // The latest edit of every cell edited since the maps were loaded, and what the first edit of
+    // the cell replaced
+    pub map_edits: Vec<MapEdit>,
+    replaced_by_map_edits: Vec<MapEdit>,
 }
 
 impl World {
-    pub fn new() -> Self {
-        Self { maps: HashMap::new() }
+    pub fn new(cell_size: u32) -> Self {
+        Self {
+            cell_size,
+            maps: HashMap::new(),
+            tile_animations: HashMap::new(),
+            tile_animation_elapsed: Duration::ZERO,
+            map_edits: Vec::new(),
+            replaced_by_map_edits: Vec::new(),
+        }
+    }
+
+    // Tile animations are defined in the custom data of the tile placed in the map, as JSON like
+    // `{"animation": {"frames": [12, 13, 14], "seconds_per_frame": 0.2}}`
+    pub fn load_tile_animations(&mut self, defs: &ldtk_project::Definitions) {
+        #[derive(Deserialize)]
+        struct TileCustomData {
+            animation: Option<TileAnimation>,
+        }
+
+        for tileset in &defs.tilesets {
+            let Some(tileset_path) = &tileset.rel_path else {
+                continue;
+            };
+
+            for custom_data in &tileset.custom_data {
+                match serde_json::from_str::<TileCustomData>(&custom_data.data) {
+                    Ok(TileCustomData { animation: Some(animation) })
+                        if !animation.frames.is_empty() =>
+                    {
+                        self.tile_animations
+                            .entry(tileset_path.clone())
+                            .or_default()
+                            .insert(custom_data.tile_id as TileId, animation);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!(
+                        "Invalid custom data of tile {} in tileset `{}` (err: {e})",
+                        custom_data.tile_id,
+                        tileset.identifier
+                    ),
+                }
+            }
+        }
+    }
+
+    // The tile to draw for a tile placed in the map, which is the current frame if it's animated
+    pub fn animated_tile_id(&self, tileset_path: &str, tile_id: TileId) -> TileId {
+        self.tile_animations
+            .get(tileset_path)
+            .and_then(|animations| animations.get(&tile_id))
+            .map_or(tile_id, |animation| animation.frame_at(self.tile_animation_elapsed))
+    }
+
+    pub fn edit_map(&mut self, edit: MapEdit) -> anyhow::Result<()> {
+        let replaced = self.swap_map_edit(edit.clone())?;
+        // (An edit of a cell that was already edited only replaces the previous edit, so that
+        // reverting still gets back to the original)
+        match self.map_edits.iter().position(|e| e.same_cell(&edit)) {
+            Some(i) => self.map_edits[i] = edit,
+            None => {
+                self.map_edits.push(edit);
+                self.replaced_by_map_edits.push(replaced);
+            }
+        }
+        Ok(())
+    }
+
+    // Back to the maps as loaded from LDtk
+    pub fn revert_map_edits(&mut self) {
+        while let Some(replaced) = self.replaced_by_map_edits.pop() {
+            self.swap_map_edit(replaced).expect("edits were valid when applied");
+        }
+        self.map_edits.clear();
+    }
+
+    // Replaces every edit with others (such as saved ones)
+    // The maps are left untouched if any of them is invalid
+    pub fn replace_map_edits(&mut self, edits: Vec<MapEdit>) -> anyhow::Result<()> {
+        let old_edits = self.map_edits.clone();
+        self.revert_map_edits();
+
+        for edit in edits {
+            if let Err(e) = self.edit_map(edit) {
+                self.revert_map_edits();
+                for old_edit in old_edits {
+                    self.edit_map(old_edit).expect("edits were valid when applied");
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes an edit, and returns the edit that would write back what it replaced
+    fn swap_map_edit(&mut self, edit: MapEdit) -> anyhow::Result<MapEdit> {
+        match edit {
+            MapEdit::Tile { map, layer, x, y, tile } => {
+                let slot = self
+                    .maps
+                    .get_mut(&map)
+                    .context(f!("no map `{map}`"))?
+                    .tile_layers
+                    .iter_mut()
+                    .find(|l| l.name == layer)
+                    .context(f!("no tile layer `{layer}` in map `{map}`"))?
+                    .tile_mut(x, y)
+                    .context(f!("tile ({x}, {y}) out of layer `{layer}` in map `{map}`"))?;
+                let replaced = std::mem::replace(slot, tile);
+                Ok(MapEdit::Tile { map, layer, x, y, tile: replaced })
+            }
+            MapEdit::Collision { map, x, y, kind } => {
+                let slot = self
+                    .maps
+                    .get_mut(&map)
+                    .context(f!("no map `{map}`"))?
+                    .collision_mut(x, y)
+                    .context(f!("collision ({x}, {y}) out of map `{map}`"))?;
+                let replaced = std::mem::replace(slot, kind);
+                Ok(MapEdit::Collision { map, x, y, kind: replaced })
+            }
+        }
+    }
+}
+
+// A change to a map made at runtime (by scripts), which is kept in save data
+// Positions are in cells of the edited tile layer or of the collision map
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum MapEdit {
+    Tile { map: String, layer: String, x: i32, y: i32, tile: Option<Tile> },
+    Collision { map: String, x: i32, y: i32, kind: Option<CollisionKind> },
+}
+
+impl MapEdit {
+    fn same_cell(&self, other: &MapEdit) -> bool {
+        match (self, other) {
+            (
+                MapEdit::Tile { map, layer, x, y, .. },
+                MapEdit::Tile {
+                    map: other_map, layer: other_layer, x: other_x, y: other_y, ..
+                },
+            ) => map == other_map && layer == other_layer && x == other_x && y == other_y,
+            (
+                MapEdit::Collision { map, x, y, .. },
+                MapEdit::Collision { map: other_map, x: other_x, y: other_y, .. },
+            ) => map == other_map && x == other_x && y == other_y,
+            _ => false,
+        }
     }
 }
 
 type TileId = u32;
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileAnimation {
+    pub frames: Vec<TileId>,
+    pub seconds_per_frame: f64,
+}
+
+impl TileAnimation {
+    fn frame_at(&self, elapsed: Duration) -> TileId {
+        let frame_index = (elapsed.as_secs_f64() / self.seconds_per_frame) as usize;
+        self.frames[frame_index % self.frames.len()]
+    }
+}
+
+// What a collision map cell does to entities with solid collision
+// Read from the identifiers of the collision_map IntGrid values in LDtk
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CollisionKind {
+    Solid,
+    // Shallow water, waded through even slower than slow terrain
+    Water,
+    // Only passable while moving in its direction
+    Ledge(Direction),
+    // Tall grass, mud, etc.
+    Slow,
+    // Doesn't block anything, only for scripts to check
+    Trigger,
+}
+
+impl CollisionKind {
+    pub fn from_identifier(identifier: &str) -> Option<Self> {
+        match identifier.to_lowercase().as_str() {
+            "solid" => Some(Self::Solid),
+            "water" => Some(Self::Water),
+            "ledge_up" => Some(Self::Ledge(Direction::Up)),
+            "ledge_down" => Some(Self::Ledge(Direction::Down)),
+            "ledge_left" => Some(Self::Ledge(Direction::Left)),
+            "ledge_right" => Some(Self::Ledge(Direction::Right)),
+            "slow" => Some(Self::Slow),
+            "trigger" => Some(Self::Trigger),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Solid => "solid",
+            Self::Water => "water",
+            Self::Ledge(Direction::Up) => "ledge_up",
+            Self::Ledge(Direction::Down) => "ledge_down",
+            Self::Ledge(Direction::Left) => "ledge_left",
+            Self::Ledge(Direction::Right) => "ledge_right",
+            Self::Slow => "slow",
+            Self::Trigger => "trigger",
+        }
+    }
+
+    // Whether an entity moving with velocity is stopped by it
+    pub fn blocks(&self, velocity: Vec2<f64, MapUnits>) -> bool {
+        match self {
+            Self::Solid => true,
+            Self::Ledge(Direction::Up) => velocity.y >= 0.,
+            Self::Ledge(Direction::Down) => velocity.y <= 0.,
+            Self::Ledge(Direction::Left) => velocity.x >= 0.,
+            Self::Ledge(Direction::Right) => velocity.x <= 0.,
+            Self::Water | Self::Slow | Self::Trigger => false,
+        }
+    }
+}
+
+// IntGrid value -> collision kind, from the collision_map layer definition
+// Values without a known identifier are solid
+fn collision_kinds(
+    defs: &ldtk_project::Definitions,
+    layer: &ldtk_project::LayerInstance,
+) -> HashMap<i64, CollisionKind> {
+    let Some(layer_def) = defs.layers.iter().find(|d| d.uid == layer.layer_def_uid) else {
+        log::error!("No layer definition for `{}`", layer.identifier);
+        return HashMap::new();
+    };
+
+    layer_def
+        .int_grid_values
+        .iter()
+        .map(|v| {
+            let identifier = v.identifier.as_deref().unwrap_or("solid");
+            let kind = CollisionKind::from_identifier(identifier).unwrap_or_else(|| {
+                log::error!("Unknown collision kind `{identifier}`, using solid");
+                CollisionKind::Solid
+            });
+            (v.value, kind)
+        })
+        .collect()
+}
+
 pub struct TileLayer {
     pub name: String,
     pub tileset_path: String,
-    pub tile_ids: Vec<Option<TileId>>,
+    // In pixels, the size of the layer's cells and of its tileset's tiles, which can differ
+    // (Tiles are drawn at their own size from the top left of their cell)
+    pub grid_size: u32,
+    pub tile_size: u32,
+    // Row by row, in layer cells
+    pub tiles: Vec<Option<Tile>>,
+    pub columns: i32,
     pub offset: Vec2<i32, PixelUnits>,
+    pub depth: LayerDepth,
+    pub opacity: f32,
+}
+
+impl TileLayer {
+    // With no tiles, covering a region of the given size in pixels
+    fn empty(
+        defs: &ldtk_project::Definitions,
+        layer: &ldtk_project::LayerInstance,
+        depth: LayerDepth,
+        size: Vec2<i32, PixelUnits>,
+    ) -> Self {
+        let grid_size = layer.grid_size as u32;
+        let tile_size = defs
+            .tilesets
+            .iter()
+            .find(|t| Some(t.uid) == layer.tileset_def_uid)
+            .map_or(grid_size, |t| t.tile_grid_size as u32);
+        let columns = size.x / grid_size as i32;
+        let rows = size.y / grid_size as i32;
+
+        Self {
+            name: layer.identifier.clone(),
+            tileset_path: layer.tileset_rel_path.as_ref().unwrap().clone(),
+            grid_size,
+            tile_size,
+            tiles: vec![None; (columns * rows) as usize],
+            columns,
+            offset: Vec2::new(layer.px_total_offset_x as i32, layer.px_total_offset_y as i32),
+            depth,
+            opacity: layer.opacity as f32,
+        }
+    }
+
+    // Places a tile by its position in pixels in the region the layer covers
+    fn set_tile(&mut self, pos: Vec2<i32, PixelUnits>, tile: Tile) {
+        let coords = pos / self.grid_size as i32;
+        if let Some(t) = self.tile_mut(coords.x, coords.y) {
+            *t = Some(tile);
+        }
+    }
+
+    // By layer cell
+    fn tile_mut(&mut self, x: i32, y: i32) -> Option<&mut Option<Tile>> {
+        if x < 0 || x >= self.columns || y < 0 {
+            return None;
+        }
+        self.tiles.get_mut((y * self.columns + x) as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tile {
+    pub id: TileId,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub alpha: f32,
+}
+
+impl Tile {
+    pub fn new(id: TileId) -> Self {
+        Self { id, flip_x: false, flip_y: false, alpha: 1. }
+    }
+
+    fn from_ldtk_tile(tile: &ldtk_project::TileInstance) -> Self {
+        Self {
+            id: tile.t as TileId,
+            flip_x: tile.f & 1 != 0,
+            flip_y: tile.f & 2 != 0,
+            alpha: tile.a as f32,
+        }
+    }
+}
+
+// Where a tile layer is drawn relative to entities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerDepth {
+    BelowEntities,
+    AboveEntities,
 }
 
 pub struct Map {
@@ -48,73 +391,137 @@ pub struct Map {
     pub dimensions: Vec2<i32, CellUnits>,
     pub offset: Vec2<i32, CellUnits>,
     pub tile_layers: Vec<TileLayer>,
-    // Option<()> so I can maybe later use an enum for different values
-    pub collisions: Vec<Option<()>>,
+    // Row by row, in cells of the collision_map layer
+    pub collisions: Vec<Option<CollisionKind>>,
+    // In map units
+    pub collision_cell_size: f64,
+    pub collision_columns: i32,
+    pub properties: MapProperties,
+}
+
+// Read from the LDtk level fields, and applied when the camera moves onto the map
+// (For a world that's a single map, from the fields of its _world_map level)
+#[derive(Clone, Default)]
+pub struct MapProperties {
+    pub display_name: Option<String>,
+    // Looping, and not restarted when the previous map has the same music
+    // (Whatever is playing carries on if none)
+    pub music: Option<String>,
+    // Looping while the camera is on the map
+    pub ambient_sfx: Option<String>,
+    pub camera_clamp: Option<bool>,
+    pub on_enter_script: Option<ScriptSource>,
+}
+
+impl MapProperties {
+    fn from_ldtk_level(level: &ldtk_project::Level) -> Self {
+        let r: anyhow::Result<Self> = try {
+            let fields = &level.field_instances;
+
+            // Scripts are external sources like `file::script_name`, like in LDtk entities
+            let on_enter_script = match read_field_instance::<String>("on_enter_script", fields)? {
+                Some(source_name) => Some(external_script_source(&source_name)?),
+                None => None,
+            };
+
+            Self {
+                display_name: read_field_instance("display_name", fields)?,
+                music: read_field_instance("music", fields)?,
+                ambient_sfx: read_field_instance("ambient_sfx", fields)?,
+                camera_clamp: read_field_instance("camera_clamp", fields)?,
+                on_enter_script,
+            }
+        };
+        r.unwrap_or_else(|e| {
+            log::error!("Invalid fields of level `{}` (err: {e})", level.identifier);
+            Self::default()
+        })
+    }
 }
 
 impl Map {
-    pub fn from_ldtk_level(level: &ldtk_project::Level) -> Self {
+    pub fn from_ldtk_level(
+        level: &ldtk_project::Level,
+        defs: &ldtk_project::Definitions,
+        cell_size: u32,
+    ) -> Self {
         let name = level.identifier.clone();
 
-        let dimensions = Vec2::new(level.px_wid as i32 / 16, level.px_hei as i32 / 16);
+        let dimensions = Vec2::new(level.px_wid as i32, level.px_hei as i32) / cell_size as i32;
         let offset = Vec2::new(0, 0);
+        let px_size = Vec2::new(level.px_wid as i32, level.px_hei as i32);
 
         let mut tile_layers: Vec<TileLayer> = Vec::new();
-        for layer in level
+        for (layer, depth) in level
             .layer_instances
             .as_ref()
             .unwrap()
             .iter()
             .rev()
             .filter(|layer| is_tile_layer(layer))
-            .filter(|layer| {
-                layer.identifier != "interiors_objects_guide"
-                    && layer.identifier != "exteriors_objects_guide"
-            })
+            .filter_map(|layer| tile_layer_depth(defs, layer).map(|depth| (layer, depth)))
         {
-            let mut tiles: Vec<Option<TileId>> = vec![None; (dimensions.x * dimensions.y) as usize];
+            let mut tile_layer = TileLayer::empty(defs, layer, depth, px_size);
             for tile in layer.grid_tiles.iter().chain(layer.auto_layer_tiles.iter()) {
-                let vec_index = (tile.px[0] as i32 / 16) + (tile.px[1] as i32 / 16) * dimensions.x;
-                *tiles.get_mut(vec_index as usize).unwrap() = Some(tile.t as u32);
+                let pos = Vec2::new(tile.px[0] as i32, tile.px[1] as i32);
+                tile_layer.set_tile(pos, Tile::from_ldtk_tile(tile));
             }
-
-            tile_layers.push(TileLayer {
-                name: layer.identifier.clone(),
-                tileset_path: layer.tileset_rel_path.as_ref().unwrap().clone(),
-                tile_ids: tiles,
-                offset: Vec2::new(layer.px_total_offset_x as i32, layer.px_total_offset_y as i32),
-            });
+            tile_layers.push(tile_layer);
         }
 
-        let collisions = level
+        let collision_layer = level
             .layer_instances
             .as_ref()
             .unwrap()
             .iter()
             .find(|l| l.identifier == "collision_map")
-            .unwrap()
+            .unwrap();
+        let kinds = collision_kinds(defs, collision_layer);
+        let collisions = collision_layer
             .int_grid_csv
             .iter()
             .map(|v| match v {
-                1 => Some(()),
-                _ => None,
+                0 => None,
+                v => Some(kinds.get(v).copied().unwrap_or(CollisionKind::Solid)),
             })
             .collect();
+        let collision_cell_size = collision_layer.grid_size as f64 / cell_size as f64;
+        let collision_columns = collision_layer.c_wid as i32;
+
+        let properties = MapProperties::from_ldtk_level(level);
 
-        Self { name, dimensions, offset, tile_layers, collisions }
+        Self {
+            name,
+            dimensions,
+            offset,
+            tile_layers,
+            collisions,
+            collision_cell_size,
+            collision_columns,
+            properties,
+        }
     }
 
-    pub fn from_ldtk_world(world: &ldtk_project::World) -> Self {
+    pub fn from_ldtk_world(
+        world: &ldtk_project::World,
+        defs: &ldtk_project::Definitions,
+        cell_size: u32,
+    ) -> Self {
         let name = world.identifier.clone();
 
-        let top = world.levels.iter().map(|l| l.world_y).min().unwrap() as i32 / 16;
-        let left = world.levels.iter().map(|l| l.world_x).min().unwrap() as i32 / 16;
-        let bottom = world.levels.iter().map(|l| l.world_y + l.px_hei).max().unwrap() as i32 / 16;
-        let right = world.levels.iter().map(|l| l.world_x + l.px_wid).max().unwrap() as i32 / 16;
+        let cs = cell_size as i64;
+        let top = (world.levels.iter().map(|l| l.world_y).min().unwrap() / cs) as i32;
+        let left = (world.levels.iter().map(|l| l.world_x).min().unwrap() / cs) as i32;
+        let bottom = (world.levels.iter().map(|l| l.world_y + l.px_hei).max().unwrap() / cs) as i32;
+        let right = (world.levels.iter().map(|l| l.world_x + l.px_wid).max().unwrap() / cs) as i32;
 
         let dimensions = Vec2::new(right - left, bottom - top);
         let offset = Vec2::new(left, top);
 
+        // Map bounds in pixels
+        let px_offset = Vec2::new(left, top) * cell_size as i32;
+        let px_size = Vec2::new(dimensions.x, dimensions.y) * cell_size as i32;
+
         // Create all the empty combined tile layers based on those of the first level.
         // It's assumed that all instances of the same definition have the same tileset
         let first_level_layers = world.levels.first().unwrap().layer_instances.as_ref().unwrap();
@@ -122,21 +529,24 @@ impl Map {
             .iter()
             .rev()
             .filter(|layer| is_tile_layer(layer))
-            .filter(|layer| {
-                layer.identifier != "interiors_objects_guide"
-                    && layer.identifier != "exteriors_objects_guide"
-            })
-            .map(|layer| TileLayer {
-                name: layer.identifier.clone(),
-                tileset_path: layer.tileset_rel_path.as_ref().unwrap().clone(),
-                tile_ids: vec![None; (dimensions.x * dimensions.y) as usize],
-                offset: Vec2::new(layer.px_total_offset_x as i32, layer.px_total_offset_y as i32),
-            })
+            .filter_map(|layer| tile_layer_depth(defs, layer).map(|depth| (layer, depth)))
+            .map(|(layer, depth)| TileLayer::empty(defs, layer, depth, px_size))
             .collect::<Vec<_>>();
 
-        let mut collisions = vec![None; (dimensions.x * dimensions.y * 2 * 2) as usize];
+        // Every level has the same collision grid, from the same layer definition
+        let collision_grid_size = defs
+            .layers
+            .iter()
+            .find(|d| d.identifier == "collision_map")
+            .map_or(cell_size as i32, |d| d.grid_size as i32);
+        let collision_cell_size = collision_grid_size as f64 / cell_size as f64;
+        let collision_columns = px_size.x / collision_grid_size;
+        let collision_rows = px_size.y / collision_grid_size;
+        let mut collisions = vec![None; (collision_columns * collision_rows) as usize];
 
         for level in &world.levels {
+            let level_pos = Vec2::new(level.world_x as i32, level.world_y as i32) - px_offset;
+
             // Populate tile layers
             for (i, layer) in level
                 .layer_instances
@@ -145,103 +555,155 @@ impl Map {
                 .iter()
                 .rev()
                 .filter(|layer| is_tile_layer(layer))
-                .filter(|layer| {
-                    layer.identifier != "interiors_objects_guide"
-                        && layer.identifier != "exteriors_objects_guide"
-                })
+                .filter(|layer| tile_layer_depth(defs, layer).is_some())
                 .enumerate()
             {
                 for tile in layer.grid_tiles.iter().chain(layer.auto_layer_tiles.iter()) {
-                    let pos_in_level = Vec2::new(tile.px[0] as i32, tile.px[1] as i32) / 16;
-                    let pos_in_world =
-                        pos_in_level + Vec2::new(level.world_x as i32, level.world_y as i32) / 16;
-                    let vec_coords = pos_in_world - offset;
-                    let vec_index = vec_coords.y * dimensions.x + vec_coords.x;
-
-                    *tile_layers
-                        .get_mut(i)
-                        .unwrap()
-                        .tile_ids
-                        .get_mut(vec_index as usize)
-                        .unwrap() = Some(tile.t as u32);
+                    let pos = Vec2::new(tile.px[0] as i32, tile.px[1] as i32) + level_pos;
+                    tile_layers.get_mut(i).unwrap().set_tile(pos, Tile::from_ldtk_tile(tile));
                 }
             }
 
             // Populate collision map
             // (skip empty _world_map level, as it will overwrite collision map where it overlaps)
             if level.identifier != "_world_map" {
-                for (i, v) in level
+                let collision_layer = level
                     .layer_instances
                     .as_ref()
                     .unwrap()
                     .iter()
                     .find(|l| l.identifier == "collision_map")
-                    .unwrap()
-                    .int_grid_csv
-                    .iter()
-                    .enumerate()
-                {
+                    .unwrap();
+                let kinds = collision_kinds(defs, collision_layer);
+                for (i, v) in collision_layer.int_grid_csv.iter().enumerate() {
                     let pos_in_level = Vec2::new(
-                        i as i32 % (level.px_wid as i32 / 16 * 2),
-                        i as i32 / (level.px_wid as i32 / 16 * 2),
+                        i as i32 % collision_layer.c_wid as i32,
+                        i as i32 / collision_layer.c_wid as i32,
                     );
-                    let pos_in_world = pos_in_level
-                        + Vec2::new(level.world_x as i32, level.world_y as i32) / 16 * 2;
-                    let vec_coords = pos_in_world - offset * 2;
-                    let vec_index = vec_coords.y * dimensions.x * 2 + vec_coords.x;
+                    let vec_coords = pos_in_level + level_pos / collision_grid_size;
+                    let vec_index = vec_coords.y * collision_columns + vec_coords.x;
 
                     *collisions.get_mut(vec_index as usize).unwrap() = match v {
-                        1 => Some(()),
-                        _ => None,
+                        0 => None,
+                        v => Some(kinds.get(v).copied().unwrap_or(CollisionKind::Solid)),
                     };
                 }
             }
         }
 
-        Self { name, dimensions, offset, tile_layers, collisions }
+        let properties = world
+            .levels
+            .iter()
+            .find(|l| l.identifier == "_world_map")
+            .map(MapProperties::from_ldtk_level)
+            .unwrap_or_default();
+
+        Self {
+            name,
+            dimensions,
+            offset,
+            tile_layers,
+            collisions,
+            collision_cell_size,
+            collision_columns,
+            properties,
+        }
     }
 
-    // Get the collision AABBs for each of the 4 quarters of a cell at cellpos
-    pub fn collision_aabbs_for_cell(&self, cell_pos: CellPos) -> [Option<Aabb>; 4] {
-        let tlc = (cell_pos - self.offset) * 2; // "top-left coords"
-        let top_left_index = tlc.y * self.dimensions.x * 2 + tlc.x;
-        let top_right_index = tlc.y * self.dimensions.x * 2 + (tlc.x + 1);
-        let bottom_left_index = (tlc.y + 1) * self.dimensions.x * 2 + tlc.x;
-        let bottom_right_index = (tlc.y + 1) * self.dimensions.x * 2 + (tlc.x + 1);
+    // The collision AABBs (and their kinds) of the collision cells overlapping a region
+    pub fn collision_aabbs_in_region(
+        &self,
+        region: &Aabb,
+    ) -> impl Iterator<Item = (Aabb, CollisionKind)> + '_ {
+        let size = self.collision_cell_size;
+        let rows = self.collisions.len() as i32 / self.collision_columns.max(1);
+        let to_coords = |x: f64, y: f64| {
+            let x = ((x - self.offset.x as f64) / size).floor() as i32;
+            let y = ((y - self.offset.y as f64) / size).floor() as i32;
+            (x.clamp(0, self.collision_columns), y.clamp(0, rows))
+        };
+        let (left, top) = to_coords(region.left, region.top);
+        let (right, bottom) = to_coords(region.right, region.bottom);
 
-        let top_left =
-            self.collisions.get(top_left_index as usize).copied().flatten().map(|()| Aabb {
-                top: cell_pos.y as f64,
-                bottom: cell_pos.y as f64 + 0.5,
-                left: cell_pos.x as f64,
-                right: cell_pos.x as f64 + 0.5,
-            });
+        (top..=bottom.min(rows - 1))
+            .flat_map(move |y| (left..=right.min(self.collision_columns - 1)).map(move |x| (x, y)))
+            .filter_map(move |(x, y)| {
+                let kind = self.collisions[(y * self.collision_columns + x) as usize]?;
+                let aabb = Aabb {
+                    top: self.offset.y as f64 + y as f64 * size,
+                    bottom: self.offset.y as f64 + (y + 1) as f64 * size,
+                    left: self.offset.x as f64 + x as f64 * size,
+                    right: self.offset.x as f64 + (x + 1) as f64 * size,
+                };
+                Some((aabb, kind))
+            })
+    }
 
-        let top_right =
-            self.collisions.get(top_right_index as usize).copied().flatten().map(|()| Aabb {
-                top: cell_pos.y as f64,
-                bottom: cell_pos.y as f64 + 0.5,
-                left: cell_pos.x as f64 + 0.5,
-                right: cell_pos.x as f64 + 1.,
-            });
+    // The collision kind of the collision cell containing map_pos
+    pub fn collision_kind_at(&self, map_pos: MapPos) -> Option<CollisionKind> {
+        let (x, y) = self.collision_coords(map_pos);
+        if x < 0 || x >= self.collision_columns || y < 0 {
+            return None;
+        }
+        let index = y * self.collision_columns + x;
+        self.collisions.get(index as usize).copied().flatten()
+    }
 
-        let bottom_left =
-            self.collisions.get(bottom_left_index as usize).copied().flatten().map(|()| Aabb {
-                top: cell_pos.y as f64 + 0.5,
-                bottom: cell_pos.y as f64 + 1.,
-                left: cell_pos.x as f64,
-                right: cell_pos.x as f64 + 0.5,
-            });
+    // The collision cell containing map_pos
+    pub fn collision_coords(&self, map_pos: MapPos) -> (i32, i32) {
+        let x = ((map_pos.x - self.offset.x as f64) / self.collision_cell_size).floor() as i32;
+        let y = ((map_pos.y - self.offset.y as f64) / self.collision_cell_size).floor() as i32;
+        (x, y)
+    }
 
-        let bottom_right =
-            self.collisions.get(bottom_right_index as usize).copied().flatten().map(|()| Aabb {
-                top: cell_pos.y as f64 + 0.5,
-                bottom: cell_pos.y as f64 + 1.,
-                left: cell_pos.x as f64 + 0.5,
-                right: cell_pos.x as f64 + 1.,
-            });
+    // The cell of a tile layer of the map containing map_pos
+    pub fn tile_coords(&self, layer: &TileLayer, map_pos: MapPos, cell_size: u32) -> (i32, i32) {
+        let px_x = (map_pos.x - self.offset.x as f64) * cell_size as f64 - layer.offset.x as f64;
+        let px_y = (map_pos.y - self.offset.y as f64) * cell_size as f64 - layer.offset.y as f64;
+        (
+            (px_x / layer.grid_size as f64).floor() as i32,
+            (px_y / layer.grid_size as f64).floor() as i32,
+        )
+    }
+
+    fn collision_mut(&mut self, x: i32, y: i32) -> Option<&mut Option<CollisionKind>> {
+        if x < 0 || x >= self.collision_columns || y < 0 {
+            return None;
+        }
+        self.collisions.get_mut((y * self.collision_columns + x) as usize)
+    }
+}
+
+// Depth follows the LDtk layer order: layers listed above the Entities layer with the
+// `entity_depth` filter tag (or else the lowest Entities layer) are drawn above entities
+// Layers with the `editor_only` filter tag (like guides) aren't drawn at all (None)
+fn tile_layer_depth(
+    defs: &ldtk_project::Definitions,
+    layer: &ldtk_project::LayerInstance,
+) -> Option<LayerDepth> {
+    let has_tag = |def: &ldtk_project::LayerDefinition, tag: &str| {
+        def.ui_filter_tags.iter().any(|t| t == tag)
+    };
+
+    let Some(index) = defs.layers.iter().position(|d| d.uid == layer.layer_def_uid) else {
+        log::error!("No layer definition for `{}`", layer.identifier);
+        return None;
+    };
+    if has_tag(&defs.layers[index], "editor_only") {
+        return None;
+    }
+
+    let entity_depth_index = defs
+        .layers
+        .iter()
+        .position(|d| has_tag(d, "entity_depth"))
+        .or_else(|| defs.layers.iter().rposition(|d| d.layer_definition_type == "Entities"))
+        .unwrap_or(0);
 
-        [top_left, top_right, bottom_left, bottom_right]
+    if index < entity_depth_index {
+        Some(LayerDepth::AboveEntities)
+    } else {
+        Some(LayerDepth::BelowEntities)
     }
 }
 
@@ -250,3 +712,91 @@ fn is_tile_layer(layer: &ldtk_project::LayerInstance) -> bool {
         || layer.layer_instance_type == "AutoLayer"
         || (layer.layer_instance_type == "IntGrid" && layer.tileset_rel_path.is_some())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CollisionKind, Map, MapEdit, MapProperties, World};
+    use crate::math::Vec2;
+    use crate::misc::Direction;
+
+    // A 2x2 map with only collisions
+    fn world() -> World {
+        let mut world = World::new(16);
+        world.maps.insert(
+            "map".to_string(),
+            Map {
+                name: "map".to_string(),
+                dimensions: Vec2::new(2, 2),
+                offset: Vec2::new(0, 0),
+                tile_layers: Vec::new(),
+                collisions: vec![Some(CollisionKind::Solid), None, None, None],
+                collision_cell_size: 1.,
+                collision_columns: 2,
+                properties: MapProperties::default(),
+            },
+        );
+        world
+    }
+
+    fn collision_edit(x: i32, y: i32, kind: Option<CollisionKind>) -> MapEdit {
+        MapEdit::Collision { map: "map".to_string(), x, y, kind }
+    }
+
+    #[test]
+    fn only_latest_edit_of_a_cell_is_kept() {
+        let mut world = world();
+        world.edit_map(collision_edit(0, 0, None)).unwrap();
+        world.edit_map(collision_edit(1, 0, Some(CollisionKind::Water))).unwrap();
+        world.edit_map(collision_edit(0, 0, Some(CollisionKind::Slow))).unwrap();
+
+        assert_eq!(world.map_edits.len(), 2);
+        assert!(matches!(
+            world.map_edits[0],
+            MapEdit::Collision { x: 0, y: 0, kind: Some(CollisionKind::Slow), .. }
+        ));
+        assert_eq!(world.maps["map"].collisions[0], Some(CollisionKind::Slow));
+
+        // Reverting gets back to before the first edit of the cell
+        world.revert_map_edits();
+        assert!(world.map_edits.is_empty());
+        assert_eq!(
+            world.maps["map"].collisions,
+            vec![Some(CollisionKind::Solid), None, None, None]
+        );
+    }
+
+    #[test]
+    fn ledges_only_block_against_their_direction() {
+        let up = Vec2::new(0., -1.);
+        let down = Vec2::new(0., 1.);
+        let left = Vec2::new(-1., 0.);
+        let right = Vec2::new(1., 0.);
+        let still = Vec2::new(0., 0.);
+
+        for (direction, passable) in [
+            (Direction::Up, up),
+            (Direction::Down, down),
+            (Direction::Left, left),
+            (Direction::Right, right),
+        ] {
+            let ledge = CollisionKind::Ledge(direction);
+            assert!(!ledge.blocks(passable), "{direction:?} ledge moving {passable:?}");
+            assert!(ledge.blocks(passable * -1.), "{direction:?} ledge moving back");
+            // Standing still or sliding along the ledge doesn't pass it
+            assert!(ledge.blocks(still), "{direction:?} ledge standing still");
+            let along = Vec2::new(passable.y, passable.x);
+            assert!(ledge.blocks(along), "{direction:?} ledge moving along");
+            assert!(ledge.blocks(along * -1.), "{direction:?} ledge moving along");
+        }
+    }
+
+    #[test]
+    fn only_solid_blocks_always() {
+        for velocity in [Vec2::new(0., 0.), Vec2::new(1., 0.), Vec2::new(0., -1.)] {
+            assert!(CollisionKind::Solid.blocks(velocity));
+            assert!(!CollisionKind::Water.blocks(velocity));
+            assert!(!CollisionKind::Slow.blocks(velocity));
+            assert!(!CollisionKind::Trigger.blocks(velocity));
+        }
+    }
+}